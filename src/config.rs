@@ -1,8 +1,10 @@
+pub use crate::serve::tokenizer::Tokenizer;
 use crate::{error::Error, proxy::Proxies};
 use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
+    time::{Duration, SystemTime},
 };
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -10,6 +12,10 @@ pub struct Config {
     /// Debug model
     pub debug: bool,
 
+    /// Log output format and sinks
+    #[serde(default)]
+    pub log: LogConfig,
+
     /// Server bind address
     pub bind: SocketAddr,
 
@@ -29,6 +35,19 @@ pub struct Config {
     /// Type: interface/proxy/cidr
     pub proxies: Vec<Proxies>,
 
+    /// Proxy pool balance strategy
+    /// Type: round_robin/random/weighted/least_in_flight/power_of_two
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+
+    /// CIDR source address rotation and client cache
+    #[serde(default)]
+    pub cidr: CidrConfig,
+
+    /// Sticky sessions, pin a caller to one upstream identity
+    #[serde(default)]
+    pub affinity: AffinityConfig,
+
     /// TLS certificate file path
     pub tls_cert: Option<PathBuf>,
 
@@ -37,22 +56,574 @@ pub struct Config {
 
     /// Authentication Key
     pub api_key: Option<String>,
+
+    /// Named API keys, stored as SHA-256 hashes
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+
+    /// Per API key and per client address rate limits
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Number of `x-vqd-4` tokens prefetched per client
+    #[serde(default = "default_token_prefetch")]
+    pub token_prefetch: usize,
+
+    /// `x-vqd-4` token time to live (seconds)
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+
+    /// How chat messages are forwarded to the upstream
+    /// Type: turns/flatten
+    #[serde(default)]
+    pub message_mode: MessageMode,
+
+    /// Upstream retry policy
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// Pool client health checking
+    #[serde(default)]
+    pub health: HealthConfig,
+
+    /// Enforcement of the JSON `response_format`
+    #[serde(default)]
+    pub response_format: ResponseFormatConfig,
+
+    /// Storage of `/v1/responses` replies, chained with `previous_response_id`
+    #[serde(default)]
+    pub responses: ResponsesConfig,
+
+    /// Batch API jobs and their files
+    #[serde(default)]
+    pub batch: BatchConfig,
+
+    /// Model catalog, served by `/v1/models` and used to map request models
+    #[serde(default = "default_models")]
+    pub models: Vec<ModelConfig>,
+
+    /// Reject unknown models with `model_not_found` instead of falling back
+    /// to the first model of the catalog
+    #[serde(default)]
+    pub reject_unknown_model: bool,
+}
+
+fn default_token_prefetch() -> usize {
+    2
+}
+
+fn default_token_ttl() -> u64 {
+    300
+}
+
+/// Log output format and sinks
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Log line format
+    /// Type: text/json
+    pub format: LogFormat,
+
+    /// Write logs to stdout
+    pub stdout: bool,
+
+    /// Write logs to a rotated file
+    pub file: Option<LogFileConfig>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            stdout: true,
+            file: None,
+        }
+    }
+}
+
+/// Log line format
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Rotated log file
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogFileConfig {
+    /// Log file path, rotated files are suffixed with the rotation time and a
+    /// sequence number when several are rotated within the same second
+    pub path: PathBuf,
+
+    /// Time based rotation
+    /// Type: never/hourly/daily
+    #[serde(default)]
+    pub rotation: LogRotation,
+
+    /// Size based rotation (megabytes)
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// Number of rotated files kept
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+fn default_log_max_files() -> usize {
+    7
+}
+
+/// Log file time based rotation
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+/// Named API key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKeyConfig {
+    /// Key name, attached to the request logs
+    pub name: String,
+
+    /// Hex encoded SHA-256 hash of the key
+    pub hash: String,
+
+    /// Models the key may use, all models if empty
+    #[serde(default)]
+    pub models: Vec<String>,
+
+    /// Disabled keys are rejected
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Expiry time (RFC 3339), the key never expires if unset
+    #[serde(default, with = "humantime_serde")]
+    pub expires_at: Option<SystemTime>,
+
+    /// Overrides `rate_limit.key` for this key
+    #[serde(default)]
+    pub rate_limit: Option<LimitConfig>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Per API key and per client address rate limits
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Limits applied to each API key
+    pub key: LimitConfig,
+
+    /// Limits applied to each client address
+    pub ip: LimitConfig,
+
+    /// Header carrying the client address when running behind a reverse
+    /// proxy, e.g. `x-forwarded-for`, the first address is used
+    pub ip_header: Option<String>,
+}
+
+/// Request limits, unset limits are not enforced
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// Requests per minute, bursts up to the same amount
+    pub requests_per_minute: Option<u32>,
+
+    /// Concurrent chat requests, streams are counted until they end
+    pub concurrent: Option<usize>,
+
+    /// Requests per UTC day
+    pub daily_requests: Option<u64>,
+}
+
+impl LimitConfig {
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none()
+            && self.concurrent.is_none()
+            && self.daily_requests.is_none()
+    }
+}
+
+/// Upstream retry policy
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: usize,
+
+    /// Initial backoff between attempts (milliseconds), doubled on each retry
+    pub backoff: u64,
+
+    /// Maximum backoff between attempts (milliseconds)
+    pub max_backoff: u64,
+
+    /// Upstream status codes that are retried
+    pub statuses: Vec<u16>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff: 200,
+            max_backoff: 2000,
+            statuses: vec![418, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryConfig {
+    #[inline]
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// Backoff before the attempt following `attempt`
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(16);
+        Duration::from_millis(self.backoff.saturating_mul(factor).min(self.max_backoff))
+    }
+}
+
+/// Enforcement of the JSON `response_format`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ResponseFormatConfig {
+    /// Number of times the upstream is asked again when its reply is not
+    /// valid JSON or does not match the schema
+    pub max_reasks: usize,
+}
+
+impl Default for ResponseFormatConfig {
+    fn default() -> Self {
+        Self { max_reasks: 2 }
+    }
+}
+
+/// Storage of `/v1/responses` replies, chained with `previous_response_id`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ResponsesConfig {
+    /// Store replies unless the request sets `store: false`, nothing is
+    /// stored if disabled
+    pub store: bool,
+
+    /// Stored response time to live (seconds), refreshed when a response is
+    /// continued
+    pub ttl: u64,
+
+    /// Maximum size of the stored conversations (megabytes), the least
+    /// recently used responses are dropped past it
+    pub max_size: u64,
+}
+
+impl Default for ResponsesConfig {
+    fn default() -> Self {
+        Self {
+            store: true,
+            ttl: 3600,
+            max_size: 256,
+        }
+    }
+}
+
+/// Batch API jobs and their files
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct BatchConfig {
+    /// Directory of uploaded files and batch jobs, created on the first
+    /// upload, unfinished batches are resumed from it on startup
+    pub dir: PathBuf,
+
+    /// Maximum number of batch requests sent to the upstream at once, across
+    /// all batches
+    pub concurrency: usize,
+
+    /// Maximum number of requests in a batch
+    pub max_requests: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("batches"),
+            concurrency: 4,
+            max_requests: 50000,
+        }
+    }
+}
+
+/// Pool client health checking
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Consecutive failures before a client is ejected
+    pub max_failures: u32,
+
+    /// Active probe interval (seconds), 0 disables probing
+    pub probe_interval: u64,
+
+    /// Initial ejection time (seconds), doubled on each consecutive ejection
+    pub ejection: u64,
+
+    /// Maximum ejection time (seconds)
+    pub max_ejection: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 3,
+            probe_interval: 30,
+            ejection: 10,
+            max_ejection: 300,
+        }
+    }
+}
+
+/// Model catalog entry
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModelConfig {
+    /// Public model id
+    pub id: String,
+
+    /// Upstream model id
+    pub upstream: String,
+
+    /// Model owner
+    pub owned_by: String,
+
+    /// Alternative public ids
+    #[serde(default)]
+    pub aliases: Vec<String>,
+
+    /// Tokenizer used for usage accounting, detected from the upstream id
+    /// if unset
+    /// Type: cl100k_base/o200k_base
+    #[serde(default)]
+    pub tokenizer: Option<Tokenizer>,
+}
+
+impl ModelConfig {
+    fn new(id: &str, upstream: &str, owned_by: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            upstream: upstream.to_owned(),
+            owned_by: owned_by.to_owned(),
+            aliases: Vec::new(),
+            tokenizer: None,
+        }
+    }
+}
+
+fn default_models() -> Vec<ModelConfig> {
+    vec![
+        ModelConfig::new("gpt-4o-mini", "gpt-4o-mini", "openai"),
+        ModelConfig::new("claude-3-haiku", "claude-3-haiku-20240307", "claude"),
+        ModelConfig::new(
+            "llama-3.3-70b",
+            "meta-llama/Llama-3.3-70B-Instruct-Turbo",
+            "meta-llama",
+        ),
+        ModelConfig::new(
+            "mistral-small-3",
+            "mistralai/Mistral-Small-24B-Instruct-2501",
+            "mistral ai",
+        ),
+        ModelConfig::new("o3-mini", "o3-mini", "openai"),
+    ]
+}
+
+/// Proxy pool balance strategy
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// Cycle through the pool members in order
+    #[default]
+    RoundRobin,
+    /// Pick a member uniformly at random
+    Random,
+    /// Pick a member at random, proportionally to its weight
+    Weighted,
+    /// Pick the member with the fewest requests in flight
+    LeastInFlight,
+    /// Pick the less loaded of two random members
+    PowerOfTwo,
+}
+
+/// CIDR source address rotation and client cache
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CidrConfig {
+    /// When the source address is replaced
+    /// Type: request/requests/interval
+    pub rotation: Rotation,
+
+    /// Maximum number of cached clients per CIDR
+    pub cache_size: u64,
+
+    /// Number of clients built ahead of rotation per CIDR
+    pub prebuild: usize,
+}
+
+impl Default for CidrConfig {
+    fn default() -> Self {
+        Self {
+            rotation: Rotation::Request,
+            cache_size: 256,
+            prebuild: 4,
+        }
+    }
+}
+
+/// CIDR source address rotation
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    /// New source address for every request
+    Request,
+    /// New source address every N requests
+    Requests(u64),
+    /// New source address every N seconds
+    Interval(u64),
+}
+
+/// Sticky sessions, pin a caller to one upstream identity
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AffinityConfig {
+    /// Affinity key source, sticky sessions are disabled if unset
+    /// Type: header/user/api_key
+    pub source: Option<AffinitySource>,
+
+    /// Header carrying the affinity key, used by the `header` source
+    pub header: String,
+
+    /// Pin time to live (seconds), refreshed on every request
+    pub ttl: u64,
+
+    /// Maximum number of pins
+    pub capacity: u64,
+}
+
+impl Default for AffinityConfig {
+    fn default() -> Self {
+        Self {
+            source: None,
+            header: "x-session-id".to_owned(),
+            ttl: 1800,
+            capacity: 10000,
+        }
+    }
+}
+
+/// Affinity key source
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AffinitySource {
+    /// Request header
+    Header,
+    /// `user` field of the request body
+    User,
+    /// API key
+    ApiKey,
+}
+
+/// How chat messages are forwarded to the upstream
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageMode {
+    /// Forward the real role/content sequence, system prompts are folded into
+    /// the next user message
+    #[default]
+    Turns,
+    /// Collapse the whole conversation into a single user message of
+    /// `role:text;` lines
+    Flatten,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             debug: false,
+            log: Default::default(),
             bind: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8080),
             timeout: 60,
             connect_timeout: 10,
             tcp_keepalive: Some(90),
             concurrent: 100,
             proxies: Default::default(),
+            balance: Default::default(),
+            cidr: Default::default(),
+            affinity: Default::default(),
             tls_cert: Default::default(),
             tls_key: Default::default(),
             api_key: Default::default(),
+            api_keys: Default::default(),
+            rate_limit: Default::default(),
+            token_prefetch: default_token_prefetch(),
+            token_ttl: default_token_ttl(),
+            message_mode: Default::default(),
+            retry: Default::default(),
+            health: Default::default(),
+            response_format: Default::default(),
+            responses: Default::default(),
+            batch: Default::default(),
+            models: default_models(),
+            reject_unknown_model: false,
+        }
+    }
+}
+
+impl Config {
+    /// Check settings that deserialize fine but cannot be served
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.models.is_empty() {
+            return Err(Error::InvalidConfig(
+                "'models' must not be empty".to_owned(),
+            ));
         }
+
+        if self.retry.max_attempts == 0 {
+            return Err(Error::InvalidConfig(
+                "'retry.max_attempts' must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.rate_limit.key.requests_per_minute == Some(0)
+            || self.rate_limit.ip.requests_per_minute == Some(0)
+            || self
+                .api_keys
+                .iter()
+                .filter_map(|key| key.rate_limit.as_ref())
+                .any(|limit| limit.requests_per_minute == Some(0))
+        {
+            return Err(Error::InvalidConfig(
+                "'requests_per_minute' must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.batch.concurrency == 0 {
+            return Err(Error::InvalidConfig(
+                "'batch.concurrency' must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(Error::InvalidConfig(
+                "'tls_cert' and 'tls_key' must be set together".to_owned(),
+            ));
+        }
+
+        Ok(())
     }
 }
 
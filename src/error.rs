@@ -19,6 +19,9 @@ pub enum Error {
     #[error(transparent)]
     SerdeYamlError(#[from] serde_yaml::Error),
 
+    #[error(transparent)]
+    SerdeJsonError(#[from] serde_json::Error),
+
     #[error(transparent)]
     RequestError(#[from] rquest::Error),
 
@@ -34,14 +37,45 @@ pub enum Error {
     #[error(transparent)]
     JsonExtractorRejection(#[from] axum::extract::rejection::JsonRejection),
 
+    #[error(transparent)]
+    MetricsBuildError(#[from] metrics_exporter_prometheus::BuildError),
+
     #[error(transparent)]
     AxumHttpError(#[from] axum::http::Error),
 
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("Unsupported proxy: {0}")]
+    InvalidProxy(String),
+
     #[error("Missing or invalid 'x-vqd-4' header")]
     MissingHeader,
 
-    #[error("{0}")]
-    BadRequest(String),
+    #[error("Upstream stream failed: {0}")]
+    UpstreamStream(String),
+
+    #[error("{1}")]
+    UpstreamStatus(rquest::StatusCode, String),
+
+    #[error("{message}")]
+    RateLimited {
+        message: String,
+        limit: u64,
+        reset: std::time::Duration,
+    },
+
+    #[error("The model did not reply in the requested 'response_format': {0}")]
+    InvalidResponseFormat(String),
+
+    #[error("{1}")]
+    InvalidParameter(&'static str, String),
+
+    #[error("The model `{0}` does not exist or you do not have access to it.")]
+    ModelNotFound(String),
+
+    #[error("No such {0} object: {1}")]
+    NotFound(&'static str, String),
 
     #[error("You didn't provide an API key. You need to provide your API key in an Authorization header using Bearer auth (i.e. Authorization: Bearer YOUR_KEY), or as the password field (with blank username) if you're accessing the API from your browser and are prompted for a username and password. You can obtain an API key from https://platform.openai.com/account/api-keys.")]
     InvalidApiKey,
@@ -1,44 +1,114 @@
 use cidr::IpCidr;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt::Debug;
 use std::net::IpAddr;
+use std::ops::Deref;
 use url::Url;
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Proxies {
     /// Upstream proxy, supports http, https, socks4, socks5, socks5h
-    URL(Url),
+    URL(Weighted<Url>),
     /// Bind to interface, supports ipv4, ipv6
-    Iface(IpAddr),
+    Iface(Weighted<IpAddr>),
     /// Bind to ipv6/ipv4 CIDR, ramdomly generate ipv4/ipv6 address
-    CIDR(IpCidr),
+    CIDR(Weighted<IpCidr>),
+}
+
+impl Proxies {
+    /// Balance weight of the proxy
+    pub fn weight(&self) -> u32 {
+        match self {
+            Proxies::URL(v) => v.weight,
+            Proxies::Iface(v) => v.weight,
+            Proxies::CIDR(v) => v.weight,
+        }
+    }
 }
 
 impl Debug for Proxies {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Proxies::URL(url) => write!(f, "{}", url),
-            Proxies::Iface(ip_addr) => write!(f, "{}", ip_addr),
-            Proxies::CIDR(cidr) => write!(f, "{}", cidr),
+            Proxies::URL(url) => write!(f, "{}", url.value),
+            Proxies::Iface(ip_addr) => write!(f, "{}", ip_addr.value),
+            Proxies::CIDR(cidr) => write!(f, "{}", cidr.value),
+        }?;
+
+        if self.weight() != 1 {
+            write!(f, " (weight {})", self.weight())?;
         }
+
+        Ok(())
     }
 }
 
 impl From<Url> for Proxies {
     fn from(url: Url) -> Self {
-        Proxies::URL(url)
+        Proxies::URL(url.into())
     }
 }
 
 impl From<IpAddr> for Proxies {
     fn from(ip_addr: IpAddr) -> Self {
-        Proxies::Iface(ip_addr)
+        Proxies::Iface(ip_addr.into())
     }
 }
 
 impl From<IpCidr> for Proxies {
     fn from(cidr: IpCidr) -> Self {
-        Proxies::CIDR(cidr)
+        Proxies::CIDR(cidr.into())
+    }
+}
+
+/// Proxy value with a balance weight, written either as the plain value
+/// or as `{ value, weight }`
+#[derive(Clone)]
+pub struct Weighted<T> {
+    pub value: T,
+    pub weight: u32,
+}
+
+impl<T> From<T> for Weighted<T> {
+    fn from(value: T) -> Self {
+        Self { value, weight: 1 }
+    }
+}
+
+impl<T> Deref for Weighted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WeightedRepr<T> {
+    Plain(T),
+    Weighted { value: T, weight: u32 },
+}
+
+impl<T: Serialize> Serialize for Weighted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.weight == 1 {
+            WeightedRepr::Plain(&self.value).serialize(serializer)
+        } else {
+            WeightedRepr::Weighted {
+                value: &self.value,
+                weight: self.weight,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Weighted<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match WeightedRepr::deserialize(deserializer)? {
+            WeightedRepr::Plain(value) => value.into(),
+            WeightedRepr::Weighted { value, weight } => Self { value, weight },
+        })
     }
 }
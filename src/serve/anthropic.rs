@@ -0,0 +1,563 @@
+//! Anthropic Messages API front-end.
+//!
+//! Requests are translated into chat requests and go through the same
+//! upstream flow, replies are rendered as Anthropic messages and typed SSE
+//! events.
+
+use super::{
+    model::{
+        random_id, ChatRequest, Content, FunctionCall, FunctionDefinition, FunctionName, Message,
+        Role, Stop, Tool, ToolCall, ToolChoice,
+    },
+    route::process::{Completion, Delta, Reply},
+};
+use crate::error::Error;
+use axum::{
+    http::StatusCode,
+    response::{sse::Event, IntoResponse, Response, Sse},
+    Json,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// ==================== Request Body ====================
+#[derive(Deserialize)]
+pub struct MessagesRequest {
+    model: String,
+
+    max_tokens: usize,
+
+    messages: Vec<InputMessage>,
+
+    #[serde(default)]
+    system: Option<System>,
+
+    #[serde(default)]
+    stop_sequences: Option<Vec<String>>,
+
+    #[serde(default)]
+    stream: Option<bool>,
+
+    #[serde(default)]
+    metadata: Option<Metadata>,
+
+    #[serde(default)]
+    tools: Option<Vec<InputTool>>,
+
+    #[serde(default)]
+    tool_choice: Option<InputToolChoice>,
+}
+
+/// System prompt, a string or text blocks
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum System {
+    Text(String),
+    Blocks(Vec<TextBlock>),
+}
+
+#[derive(Deserialize)]
+struct TextBlock {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct InputMessage {
+    role: InputRole,
+    content: InputContent,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InputRole {
+    User,
+    Assistant,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputContent {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default)]
+        content: Option<InputContent>,
+        #[serde(default)]
+        is_error: bool,
+    },
+    /// Images and documents, the upstream only takes text
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(default)]
+    user_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InputTool {
+    name: String,
+    #[serde(default)]
+    description: Option<String>,
+    input_schema: Value,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum InputToolChoice {
+    Auto,
+    Any,
+    Tool { name: String },
+    None,
+}
+
+impl MessagesRequest {
+    pub fn stream(&self) -> bool {
+        self.stream.unwrap_or_default()
+    }
+
+    /// Translate into a chat request, content blocks are flattened into text,
+    /// tool uses and results into tool calls and tool messages
+    pub fn into_chat_request(self) -> crate::Result<ChatRequest> {
+        let mut messages = Vec::with_capacity(self.messages.len() + 1);
+        if let Some(system) = self.system {
+            let text = match system {
+                System::Text(text) => text,
+                System::Blocks(blocks) => blocks
+                    .into_iter()
+                    .map(|block| block.text)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            };
+            messages.push(
+                Message::builder()
+                    .role(Role::System)
+                    .content(Content::Text(text))
+                    .build(),
+            );
+        }
+
+        for message in self.messages {
+            let blocks = match message.content {
+                InputContent::Text(text) => vec![ContentBlock::Text { text }],
+                InputContent::Blocks(blocks) => blocks,
+            };
+
+            let mut texts = Vec::new();
+            let mut tool_calls = Vec::new();
+            for block in blocks {
+                match block {
+                    ContentBlock::Text { text } => texts.push(text),
+                    ContentBlock::ToolUse { id, name, input } => tool_calls.push(
+                        ToolCall::builder()
+                            .id(id)
+                            .function(FunctionCall {
+                                name,
+                                arguments: input.to_string(),
+                            })
+                            .build(),
+                    ),
+                    // Tool results come first in a user turn, sent as tool
+                    // messages ahead of its text
+                    ContentBlock::ToolResult {
+                        tool_use_id,
+                        content,
+                        is_error,
+                    } => {
+                        let mut text = content.map(text_of).transpose()?.unwrap_or_default();
+                        if is_error {
+                            text.insert_str(0, "Error: ");
+                        }
+                        messages.push(
+                            Message::builder()
+                                .role(Role::Tool)
+                                .content(Content::Text(text))
+                                .tool_call_id(tool_use_id)
+                                .build(),
+                        );
+                    }
+                    ContentBlock::Unsupported => return Err(unsupported_block()),
+                }
+            }
+
+            let role = match message.role {
+                InputRole::User => Role::User,
+                InputRole::Assistant => Role::Assistant,
+            };
+            if texts.is_empty() && tool_calls.is_empty() {
+                continue;
+            }
+            messages.push(
+                Message::builder()
+                    .role(role)
+                    .content(Content::Text(texts.join("\n")))
+                    .tool_calls((!tool_calls.is_empty()).then_some(tool_calls))
+                    .build(),
+            );
+        }
+
+        let tools = self.tools.map(|tools| {
+            tools
+                .into_iter()
+                .map(|tool| Tool {
+                    r#type: "function".to_owned(),
+                    function: FunctionDefinition {
+                        name: tool.name,
+                        description: tool.description,
+                        parameters: Some(tool.input_schema),
+                    },
+                })
+                .collect::<Vec<_>>()
+        });
+        let tool_choice = self.tool_choice.map(|choice| match choice {
+            InputToolChoice::Auto => ToolChoice::Mode("auto".to_owned()),
+            InputToolChoice::Any => ToolChoice::Mode("required".to_owned()),
+            InputToolChoice::None => ToolChoice::Mode("none".to_owned()),
+            InputToolChoice::Tool { name } => ToolChoice::Function {
+                function: FunctionName { name },
+            },
+        });
+
+        Ok(ChatRequest::builder()
+            .model(self.model)
+            .messages(messages)
+            .stream(self.stream)
+            .user(self.metadata.and_then(|metadata| metadata.user_id))
+            .stop(self.stop_sequences.map(Stop::Many))
+            .max_tokens(self.max_tokens)
+            .tools(tools)
+            .tool_choice(tool_choice)
+            .build())
+    }
+}
+
+/// Text of a tool result
+fn text_of(content: InputContent) -> crate::Result<String> {
+    match content {
+        InputContent::Text(text) => Ok(text),
+        InputContent::Blocks(blocks) => blocks
+            .into_iter()
+            .map(|block| match block {
+                ContentBlock::Text { text } => Ok(text),
+                _ => Err(unsupported_block()),
+            })
+            .collect::<crate::Result<Vec<_>>>()
+            .map(|texts| texts.join("\n")),
+    }
+}
+
+fn unsupported_block() -> Error {
+    Error::InvalidParameter(
+        "messages",
+        "Only text, tool_use and tool_result content blocks are supported".to_owned(),
+    )
+}
+
+// ==================== Response Body ====================
+#[derive(Serialize)]
+struct MessageResponse<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    r#type: &'static str,
+    role: &'static str,
+    model: &'a str,
+    content: Vec<OutputBlock<'a>>,
+    stop_reason: Option<&'static str>,
+    stop_sequence: Option<&'a str>,
+    usage: OutputUsage,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputBlock<'a> {
+    Text {
+        text: &'a str,
+    },
+    ToolUse {
+        id: &'a str,
+        name: &'a str,
+        input: Value,
+    },
+}
+
+#[derive(Serialize)]
+struct OutputUsage {
+    input_tokens: usize,
+    output_tokens: usize,
+}
+
+/// Typed SSE events, the event name is the `type` field
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent<'a> {
+    MessageStart {
+        message: MessageResponse<'a>,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: OutputBlock<'a>,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta<'a>,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDelta<'a>,
+        usage: DeltaUsage,
+    },
+    MessageStop,
+    Error {
+        error: ErrorDetail,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta<'a> {
+    TextDelta { text: &'a str },
+    InputJsonDelta { partial_json: &'a str },
+}
+
+#[derive(Serialize)]
+struct MessageDelta<'a> {
+    stop_reason: &'static str,
+    stop_sequence: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct DeltaUsage {
+    output_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail {
+    #[serde(rename = "type")]
+    r#type: &'static str,
+    message: String,
+}
+
+impl StreamEvent<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::MessageStart { .. } => "message_start",
+            StreamEvent::ContentBlockStart { .. } => "content_block_start",
+            StreamEvent::ContentBlockDelta { .. } => "content_block_delta",
+            StreamEvent::ContentBlockStop { .. } => "content_block_stop",
+            StreamEvent::MessageDelta { .. } => "message_delta",
+            StreamEvent::MessageStop => "message_stop",
+            StreamEvent::Error { .. } => "error",
+        }
+    }
+
+    fn into_event(self) -> Result<Event, axum::Error> {
+        Event::default()
+            .event(self.name())
+            .json_data(self)
+            .map_err(axum::Error::new)
+    }
+}
+
+/// Anthropic message of a whole reply
+pub fn message(completion: Completion) -> Response {
+    let id = random_id("msg");
+    let usage = completion.usage();
+    let content = match completion.tool_calls() {
+        Some(calls) => calls.iter().map(tool_use).collect(),
+        None => vec![OutputBlock::Text {
+            text: completion.text(),
+        }],
+    };
+
+    Json(MessageResponse {
+        id: &id,
+        r#type: "message",
+        role: "assistant",
+        model: completion.model(),
+        content,
+        stop_reason: Some(stop_reason(
+            completion.finish_reason(),
+            completion.stop_sequence(),
+        )),
+        stop_sequence: completion.stop_sequence(),
+        usage: OutputUsage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        },
+    })
+    .into_response()
+}
+
+/// Anthropic typed SSE events of a reply
+pub fn message_stream(reply: Reply) -> Response {
+    let model = reply.model().to_owned();
+    let input_tokens = reply.prompt_tokens();
+    let deltas = reply.deltas();
+    let sse_stream = async_stream::stream! {
+        let id = random_id("msg");
+        let mut index = 0;
+        let mut text_open = false;
+
+        futures_util::pin_mut!(deltas);
+        while let Some(delta) = deltas.next().await {
+            match delta {
+                Delta::Start { .. } => {
+                    yield StreamEvent::MessageStart {
+                        message: MessageResponse {
+                            id: &id,
+                            r#type: "message",
+                            role: "assistant",
+                            model: &model,
+                            content: Vec::new(),
+                            stop_reason: None,
+                            stop_sequence: None,
+                            usage: OutputUsage {
+                                input_tokens,
+                                output_tokens: 0,
+                            },
+                        },
+                    }
+                    .into_event();
+                }
+                Delta::Text(text) => {
+                    if !text_open {
+                        text_open = true;
+                        yield StreamEvent::ContentBlockStart {
+                            index,
+                            content_block: OutputBlock::Text { text: "" },
+                        }
+                        .into_event();
+                    }
+                    yield StreamEvent::ContentBlockDelta {
+                        index,
+                        delta: BlockDelta::TextDelta { text: &text },
+                    }
+                    .into_event();
+                }
+                Delta::ToolCalls(calls) => {
+                    if text_open {
+                        text_open = false;
+                        yield StreamEvent::ContentBlockStop { index }.into_event();
+                        index += 1;
+                    }
+                    for call in calls {
+                        yield StreamEvent::ContentBlockStart {
+                            index,
+                            content_block: OutputBlock::ToolUse {
+                                id: &call.id,
+                                name: &call.function.name,
+                                input: Value::Object(Default::default()),
+                            },
+                        }
+                        .into_event();
+                        yield StreamEvent::ContentBlockDelta {
+                            index,
+                            delta: BlockDelta::InputJsonDelta {
+                                partial_json: &call.function.arguments,
+                            },
+                        }
+                        .into_event();
+                        yield StreamEvent::ContentBlockStop { index }.into_event();
+                        index += 1;
+                    }
+                }
+                // The stream ends with the error event
+                Delta::Error(err) => {
+                    yield error_event(&err).into_event();
+                    break;
+                }
+                Delta::Finish {
+                    finish_reason,
+                    stop_sequence,
+                    usage,
+                } => {
+                    if text_open {
+                        text_open = false;
+                        yield StreamEvent::ContentBlockStop { index }.into_event();
+                    }
+                    yield StreamEvent::MessageDelta {
+                        delta: MessageDelta {
+                            stop_reason: stop_reason(finish_reason, stop_sequence.as_deref()),
+                            stop_sequence: stop_sequence.as_deref(),
+                        },
+                        usage: DeltaUsage {
+                            output_tokens: usage.completion_tokens,
+                        },
+                    }
+                    .into_event();
+                    yield StreamEvent::MessageStop.into_event();
+                }
+            }
+        }
+    };
+
+    Sse::new(sse_stream).into_response()
+}
+
+/// Anthropic error response
+pub fn error_response(err: Error) -> Response {
+    let (status, headers) = (err.to_openai().0, err.headers());
+    (status, headers, Json(error_event(&err))).into_response()
+}
+
+fn error_event(err: &Error) -> StreamEvent<'static> {
+    let (status, error) = err.to_openai();
+    StreamEvent::Error {
+        error: ErrorDetail {
+            r#type: error_type(status),
+            message: error.message().to_owned(),
+        },
+    }
+}
+
+/// Anthropic error type of an HTTP status
+fn error_type(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        400..=499 => "invalid_request_error",
+        _ => "api_error",
+    }
+}
+
+/// Anthropic stop reason of a finish reason
+fn stop_reason(finish_reason: &str, stop_sequence: Option<&str>) -> &'static str {
+    match finish_reason {
+        "tool_calls" => "tool_use",
+        "length" => "max_tokens",
+        _ if stop_sequence.is_some() => "stop_sequence",
+        _ => "end_turn",
+    }
+}
+
+fn tool_use(call: &ToolCall) -> OutputBlock<'_> {
+    OutputBlock::ToolUse {
+        id: &call.id,
+        name: &call.function.name,
+        input: serde_json::from_str(&call.function.arguments)
+            .unwrap_or_else(|_| Value::Object(Default::default())),
+    }
+}
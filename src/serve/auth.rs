@@ -0,0 +1,148 @@
+use crate::{
+    config::{ApiKeyConfig, Config, LimitConfig, ModelConfig},
+    error::Error,
+};
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+
+/// API key with its metadata, only the SHA-256 digest of the key is kept
+pub struct ApiKey {
+    name: String,
+    digest: [u8; 32],
+    models: Vec<String>,
+    enabled: bool,
+    expires_at: Option<SystemTime>,
+    rate_limit: Option<LimitConfig>,
+}
+
+impl ApiKey {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Rate limits overriding the shared per-key limits
+    #[inline]
+    pub fn rate_limit(&self) -> Option<&LimitConfig> {
+        self.rate_limit.as_ref()
+    }
+
+    /// Whether the key may use the model, an empty list allows every model
+    pub fn allows(&self, model: &ModelConfig) -> bool {
+        self.models.is_empty()
+            || self
+                .models
+                .iter()
+                .any(|m| *m == model.id || model.aliases.contains(m))
+    }
+
+    /// Check the key is enabled and has not expired
+    fn check(&self) -> crate::Result<&Self> {
+        if !self.enabled {
+            tracing::warn!("API key '{}' is disabled", self.name);
+            return Err(Error::InvalidApiKey);
+        }
+
+        if self
+            .expires_at
+            .map_or(false, |expires_at| SystemTime::now() >= expires_at)
+        {
+            tracing::warn!("API key '{}' has expired", self.name);
+            return Err(Error::InvalidApiKey);
+        }
+
+        Ok(self)
+    }
+
+    /// Check the key may use the model
+    pub fn allow_model(&self, model: &ModelConfig) -> crate::Result<()> {
+        if self.allows(model) {
+            Ok(())
+        } else {
+            Err(Error::ModelNotFound(model.id.clone()))
+        }
+    }
+}
+
+/// API key store
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    pub fn new(config: &Config) -> crate::Result<Self> {
+        let mut keys = Vec::with_capacity(config.api_keys.len() + 1);
+
+        // Single plaintext key
+        if let Some(ref key) = config.api_key {
+            keys.push(ApiKey {
+                name: "default".to_owned(),
+                digest: Sha256::digest(key.as_bytes()).into(),
+                models: Vec::new(),
+                enabled: true,
+                expires_at: None,
+                rate_limit: None,
+            });
+        }
+
+        for key in config.api_keys.iter() {
+            keys.push(ApiKey {
+                name: key.name.clone(),
+                digest: parse_digest(key)?,
+                models: key.models.clone(),
+                enabled: key.enabled,
+                expires_at: key.expires_at,
+                rate_limit: key.rate_limit.clone(),
+            });
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// Authenticate a bearer token, returns `None` when no key is configured
+    pub fn authenticate(&self, token: Option<&str>) -> crate::Result<Option<&ApiKey>> {
+        if self.keys.is_empty() {
+            return Ok(None);
+        }
+
+        let token = token.ok_or(Error::InvalidApiKey)?;
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+
+        // Compare against every key so that timing does not depend on the match
+        let mut matched = None;
+        for key in self.keys.iter() {
+            if bool::from(key.digest.ct_eq(&digest)) {
+                matched = Some(key);
+            }
+        }
+
+        matched.ok_or(Error::InvalidApiKey)?.check().map(Some)
+    }
+
+    /// Key by name, fails if it has been removed, disabled or has expired
+    pub fn find(&self, name: &str) -> crate::Result<&ApiKey> {
+        self.keys
+            .iter()
+            .find(|key| key.name == name)
+            .ok_or(Error::InvalidApiKey)?
+            .check()
+    }
+}
+
+/// Parse a hex encoded SHA-256 digest, optionally prefixed with `sha256:`
+fn parse_digest(key: &ApiKeyConfig) -> crate::Result<[u8; 32]> {
+    let hex = key.hash.strip_prefix("sha256:").unwrap_or(&key.hash);
+    let invalid = || Error::InvalidConfig(format!("api key '{}' has an invalid hash", key.name));
+
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(digest)
+}
@@ -0,0 +1,1068 @@
+//! OpenAI Batch API.
+//!
+//! JSONL files of chat requests are uploaded to `/v1/files` and run in the
+//! background by `/v1/batches` jobs, with a bounded number of requests in
+//! flight. Files and jobs are kept on disk and results are appended as they
+//! complete, so that unfinished batches resume after a restart without
+//! sending their finished requests again.
+
+use super::{
+    limit::Permit,
+    model::{random_id, ChatRequest},
+    route, AppState, Runtime,
+};
+use crate::{config::BatchConfig, error::Error};
+use axum::{
+    body::to_bytes,
+    extract::{multipart::MultipartError, Multipart},
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::{Mutex, Semaphore},
+};
+
+/// Only endpoint batch requests may target
+const ENDPOINT: &str = "/v1/chat/completions";
+
+/// Only completion window, requests not sent within it expire
+const COMPLETION_WINDOW: &str = "24h";
+const COMPLETION_WINDOW_SECS: u64 = 86400;
+
+/// Longest wait on the rate limits of the batch owner before checking the
+/// batch again, so that a cancellation is not held up by a daily limit
+const LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+// ==================== Request Body ====================
+#[derive(Deserialize)]
+pub struct CreateBatch {
+    input_file_id: String,
+    endpoint: String,
+    completion_window: String,
+    #[serde(default)]
+    metadata: Option<Map<String, Value>>,
+}
+
+#[derive(Deserialize)]
+pub struct ListFiles {
+    #[serde(default)]
+    purpose: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListBatches {
+    #[serde(default)]
+    after: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Line of an input file
+#[derive(Deserialize)]
+struct RequestLine {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: ChatRequest,
+}
+
+// ==================== Response Body ====================
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileObject {
+    id: String,
+    object: String,
+    bytes: u64,
+    created_at: u64,
+    filename: String,
+    purpose: String,
+    status: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Batch {
+    id: String,
+    object: String,
+    endpoint: String,
+    errors: Option<BatchErrors>,
+    input_file_id: String,
+    completion_window: String,
+    status: BatchStatus,
+    output_file_id: Option<String>,
+    error_file_id: Option<String>,
+    created_at: u64,
+    in_progress_at: Option<u64>,
+    expires_at: u64,
+    finalizing_at: Option<u64>,
+    completed_at: Option<u64>,
+    failed_at: Option<u64>,
+    expired_at: Option<u64>,
+    cancelling_at: Option<u64>,
+    cancelled_at: Option<u64>,
+    request_counts: RequestCounts,
+    metadata: Option<Map<String, Value>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct RequestCounts {
+    total: usize,
+    completed: usize,
+    failed: usize,
+}
+
+/// Validation errors of the input file
+#[derive(Serialize, Deserialize, Clone)]
+struct BatchErrors {
+    object: String,
+    data: Vec<BatchError>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BatchError {
+    code: String,
+    message: String,
+    param: Option<String>,
+    line: Option<usize>,
+}
+
+/// Line of an output or error file
+#[derive(Serialize, Deserialize)]
+struct ResultLine {
+    id: String,
+    custom_id: String,
+    response: Option<ResultResponse>,
+    error: Option<ResultError>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResultResponse {
+    status_code: u16,
+    request_id: String,
+    body: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResultError {
+    code: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+pub struct List<T> {
+    object: &'static str,
+    first_id: Option<String>,
+    last_id: Option<String>,
+    has_more: bool,
+    data: Vec<T>,
+}
+
+#[derive(Serialize)]
+pub struct Deleted {
+    id: String,
+    object: &'static str,
+    deleted: bool,
+}
+
+impl BatchStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            BatchStatus::Validating => "validating",
+            BatchStatus::Failed => "failed",
+            BatchStatus::InProgress => "in_progress",
+            BatchStatus::Finalizing => "finalizing",
+            BatchStatus::Completed => "completed",
+            BatchStatus::Expired => "expired",
+            BatchStatus::Cancelling => "cancelling",
+            BatchStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl BatchErrors {
+    fn new(data: Vec<BatchError>) -> Self {
+        Self {
+            object: "list".to_owned(),
+            data,
+        }
+    }
+}
+
+impl BatchError {
+    fn new(code: &str, message: String, param: Option<&str>, line: Option<usize>) -> Self {
+        Self {
+            code: code.to_owned(),
+            message,
+            param: param.map(str::to_owned),
+            line,
+        }
+    }
+}
+
+impl ResultResponse {
+    /// Status and JSON body of a response
+    async fn read(resp: Response) -> Self {
+        let status_code = resp.status().as_u16();
+        let body = to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .ok()
+            .and_then(|body| serde_json::from_slice(&body).ok())
+            .unwrap_or_default();
+        Self {
+            status_code,
+            request_id: random_id("req"),
+            body,
+        }
+    }
+}
+
+impl<T> List<T> {
+    fn new(data: Vec<T>, has_more: bool, id: impl Fn(&T) -> &str) -> Self {
+        Self {
+            object: "list",
+            first_id: data.first().map(|item| id(item).to_owned()),
+            last_id: data.last().map(|item| id(item).to_owned()),
+            has_more,
+            data,
+        }
+    }
+}
+
+// ==================== Store ====================
+/// Object as written to disk, with the name of the API key owning it
+#[derive(Serialize, Deserialize)]
+struct Record<T> {
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(flatten)]
+    object: T,
+}
+
+/// Batch job, the batch is written to disk on every status change
+struct Job {
+    owner: Option<String>,
+    batch: Mutex<Batch>,
+}
+
+/// Files and batch jobs, kept in memory and on disk
+pub struct BatchStore {
+    dir: PathBuf,
+    concurrency: usize,
+    max_requests: usize,
+    /// Batch requests in flight across all batches
+    permits: Semaphore,
+    files: RwLock<HashMap<String, Arc<Record<FileObject>>>>,
+    batches: RwLock<HashMap<String, Arc<Job>>>,
+}
+
+impl BatchStore {
+    /// Load the files and batches of the directory, which is only created
+    /// once the Batch API is used
+    pub async fn open(config: &BatchConfig) -> crate::Result<Self> {
+        let dir = config.dir.clone();
+        let files = read_records::<FileObject>(&dir.join("files"))
+            .await?
+            .into_iter()
+            .map(|record| (record.object.id.clone(), Arc::new(record)))
+            .collect();
+        let batches = read_records::<Batch>(&dir.join("batches"))
+            .await?
+            .into_iter()
+            .map(|record| {
+                let id = record.object.id.clone();
+                let job = Job {
+                    owner: record.owner,
+                    batch: Mutex::new(record.object),
+                };
+                (id, Arc::new(job))
+            })
+            .collect();
+
+        Ok(Self {
+            dir,
+            concurrency: config.concurrency,
+            max_requests: config.max_requests,
+            permits: Semaphore::new(config.concurrency),
+            files: RwLock::new(files),
+            batches: RwLock::new(batches),
+        })
+    }
+
+    /// Store an uploaded file
+    pub async fn upload(
+        &self,
+        owner: Option<String>,
+        mut multipart: Multipart,
+    ) -> crate::Result<FileObject> {
+        let (mut purpose, mut file) = (None, None);
+        while let Some(field) = multipart.next_field().await.map_err(invalid_upload)? {
+            match field.name() {
+                Some("purpose") => purpose = Some(field.text().await.map_err(invalid_upload)?),
+                Some("file") => {
+                    let filename = field.file_name().unwrap_or("input.jsonl").to_owned();
+                    file = Some((filename, field.bytes().await.map_err(invalid_upload)?));
+                }
+                _ => {}
+            }
+        }
+
+        if purpose.as_deref() != Some("batch") {
+            return Err(Error::InvalidParameter(
+                "purpose",
+                "Only files with purpose 'batch' are supported".to_owned(),
+            ));
+        }
+        let (filename, data) = file.ok_or_else(|| {
+            Error::InvalidParameter("file", "The request has no 'file' part".to_owned())
+        })?;
+        self.create_dirs().await?;
+
+        let file = FileObject {
+            id: random_id("file"),
+            object: "file".to_owned(),
+            bytes: data.len() as u64,
+            created_at: now(),
+            filename,
+            purpose: "batch".to_owned(),
+            status: "processed".to_owned(),
+        };
+        fs::write(self.content_path(&file.id), &data).await?;
+        self.insert_file(owner, file.clone()).await?;
+        Ok(file)
+    }
+
+    /// Files of the owner, most recent first
+    pub fn files(&self, owner: Option<&str>, query: &ListFiles) -> List<FileObject> {
+        let mut files = self
+            .files
+            .read()
+            .unwrap()
+            .values()
+            .filter(|record| record.owner.as_deref() == owner)
+            .filter(|record| {
+                query
+                    .purpose
+                    .as_ref()
+                    .map_or(true, |purpose| record.object.purpose == *purpose)
+            })
+            .map(|record| record.object.clone())
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        List::new(files, false, |file| file.id.as_str())
+    }
+
+    pub fn file(&self, id: &str, owner: Option<&str>) -> crate::Result<FileObject> {
+        self.owned_file(id, owner)
+            .map(|record| record.object.clone())
+    }
+
+    pub async fn file_content(&self, id: &str, owner: Option<&str>) -> crate::Result<Vec<u8>> {
+        let record = self.owned_file(id, owner)?;
+        Ok(fs::read(self.content_path(&record.object.id)).await?)
+    }
+
+    pub async fn delete_file(&self, id: &str, owner: Option<&str>) -> crate::Result<Deleted> {
+        let record = self.owned_file(id, owner)?;
+        let id = record.object.id.clone();
+        self.files.write().unwrap().remove(&id);
+        fs::remove_file(self.record_path("files", &id)).await?;
+        fs::remove_file(self.content_path(&id)).await?;
+        Ok(Deleted {
+            id,
+            object: "file",
+            deleted: true,
+        })
+    }
+
+    /// Batches of the owner, most recent first, paginated after a batch id
+    pub async fn batches(&self, owner: Option<&str>, query: &ListBatches) -> List<Batch> {
+        let jobs = self
+            .batches
+            .read()
+            .unwrap()
+            .values()
+            .filter(|job| job.owner.as_deref() == owner)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut batches = Vec::with_capacity(jobs.len());
+        for job in jobs {
+            batches.push(job.batch.lock().await.clone());
+        }
+        batches.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+
+        if let Some(ref after) = query.after {
+            if let Some(position) = batches.iter().position(|batch| batch.id == *after) {
+                batches.drain(..=position);
+            }
+        }
+        let limit = query.limit.unwrap_or(20).clamp(1, 100);
+        let has_more = batches.len() > limit;
+        batches.truncate(limit);
+        List::new(batches, has_more, |batch| batch.id.as_str())
+    }
+
+    pub async fn batch(&self, id: &str, owner: Option<&str>) -> crate::Result<Batch> {
+        let job = self.job(id, owner)?;
+        let batch = job.batch.lock().await.clone();
+        Ok(batch)
+    }
+
+    /// Stop sending the requests of a batch, the results so far are kept
+    pub async fn cancel(&self, id: &str, owner: Option<&str>) -> crate::Result<Batch> {
+        let job = self.job(id, owner)?;
+        let batch = self
+            .update(&job, |batch| {
+                if matches!(
+                    batch.status,
+                    BatchStatus::Validating | BatchStatus::InProgress
+                ) {
+                    batch.status = BatchStatus::Cancelling;
+                    batch.cancelling_at = Some(now());
+                }
+            })
+            .await?;
+
+        match batch.status {
+            BatchStatus::Cancelling | BatchStatus::Cancelled => Ok(batch),
+            status => Err(Error::InvalidParameter(
+                "batch_id",
+                format!("Cannot cancel a batch with status '{}'.", status.as_str()),
+            )),
+        }
+    }
+
+    async fn create_dirs(&self) -> crate::Result<()> {
+        fs::create_dir_all(self.dir.join("files")).await?;
+        fs::create_dir_all(self.dir.join("batches")).await?;
+        Ok(())
+    }
+
+    fn owned_file(&self, id: &str, owner: Option<&str>) -> crate::Result<Arc<Record<FileObject>>> {
+        self.files
+            .read()
+            .unwrap()
+            .get(id)
+            .filter(|record| record.owner.as_deref() == owner)
+            .cloned()
+            .ok_or_else(|| Error::NotFound("File", id.to_owned()))
+    }
+
+    fn job(&self, id: &str, owner: Option<&str>) -> crate::Result<Arc<Job>> {
+        self.batches
+            .read()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.owner.as_deref() == owner)
+            .cloned()
+            .ok_or_else(|| Error::NotFound("Batch", id.to_owned()))
+    }
+
+    async fn insert_file(&self, owner: Option<String>, file: FileObject) -> crate::Result<()> {
+        let record = Record {
+            owner,
+            object: file,
+        };
+        write_record(&self.record_path("files", &record.object.id), &record).await?;
+        self.files
+            .write()
+            .unwrap()
+            .insert(record.object.id.clone(), Arc::new(record));
+        Ok(())
+    }
+
+    /// Apply a change to the batch and write it to disk
+    async fn update(&self, job: &Job, change: impl FnOnce(&mut Batch)) -> crate::Result<Batch> {
+        let mut batch = job.batch.lock().await;
+        change(&mut batch);
+        let record = Record {
+            owner: job.owner.clone(),
+            object: &*batch,
+        };
+        write_record(&self.record_path("batches", &batch.id), &record).await?;
+        Ok(batch.clone())
+    }
+
+    /// Register a non-empty results file of a batch as a `batch_output` file.
+    /// The file id is derived from the batch, so that publishing again after a
+    /// restart midway registers the same file
+    async fn publish(
+        &self,
+        batch_id: &str,
+        kind: &str,
+        owner: Option<&str>,
+    ) -> crate::Result<Option<String>> {
+        let results = self.results_path(batch_id, kind);
+        if file_len(&results).await? == Some(0) {
+            fs::remove_file(&results).await?;
+        }
+
+        let id = published_id(batch_id, kind);
+        if self.files.read().unwrap().contains_key(&id) {
+            return Ok(Some(id));
+        }
+
+        let content = self.content_path(&id);
+        let bytes = match file_len(&content).await? {
+            Some(bytes) => bytes,
+            None => match file_len(&results).await? {
+                Some(bytes) => {
+                    fs::rename(&results, &content).await?;
+                    bytes
+                }
+                None => return Ok(None),
+            },
+        };
+
+        let file = FileObject {
+            id: id.clone(),
+            object: "file".to_owned(),
+            bytes,
+            created_at: now(),
+            filename: format!("{batch_id}_{kind}.jsonl"),
+            purpose: "batch_output".to_owned(),
+            status: "processed".to_owned(),
+        };
+        self.insert_file(owner.map(str::to_owned), file).await?;
+        Ok(Some(id))
+    }
+
+    /// Custom ids of the results of a batch, `output` or `error`, and whether
+    /// any request expired. Results already published by a run interrupted
+    /// while finalizing are included
+    async fn results(&self, batch_id: &str, kind: &str) -> crate::Result<(HashSet<String>, bool)> {
+        let (mut custom_ids, mut expired) =
+            read_results(&self.results_path(batch_id, kind)).await?;
+        let published = self.content_path(&published_id(batch_id, kind));
+        let (published, published_expired) = read_results(&published).await?;
+        custom_ids.extend(published);
+        expired |= published_expired;
+        Ok((custom_ids, expired))
+    }
+
+    fn record_path(&self, kind: &str, id: &str) -> PathBuf {
+        self.dir.join(kind).join(format!("{id}.json"))
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.dir.join("files").join(format!("{id}.jsonl"))
+    }
+
+    /// Results appended while a batch runs, `output` or `error`
+    fn results_path(&self, batch_id: &str, kind: &str) -> PathBuf {
+        self.dir
+            .join("batches")
+            .join(format!("{batch_id}.{kind}.jsonl"))
+    }
+}
+
+// ==================== Runner ====================
+/// Create a batch and start running it
+pub async fn create(
+    state: &AppState,
+    owner: Option<String>,
+    request: CreateBatch,
+) -> crate::Result<Batch> {
+    if request.endpoint != ENDPOINT {
+        return Err(Error::InvalidParameter(
+            "endpoint",
+            format!(
+                "Unsupported endpoint '{}', only '{ENDPOINT}' is supported",
+                request.endpoint
+            ),
+        ));
+    }
+    if request.completion_window != COMPLETION_WINDOW {
+        return Err(Error::InvalidParameter(
+            "completion_window",
+            format!("Only a '{COMPLETION_WINDOW}' completion window is supported"),
+        ));
+    }
+
+    let store = state.batches();
+    let input = store.owned_file(&request.input_file_id, owner.as_deref())?;
+    if input.object.purpose != "batch" {
+        return Err(Error::InvalidParameter(
+            "input_file_id",
+            "The input file must be uploaded with purpose 'batch'".to_owned(),
+        ));
+    }
+
+    let created_at = now();
+    let batch = Batch {
+        id: random_id("batch"),
+        object: "batch".to_owned(),
+        endpoint: request.endpoint,
+        errors: None,
+        input_file_id: request.input_file_id,
+        completion_window: request.completion_window,
+        status: BatchStatus::Validating,
+        output_file_id: None,
+        error_file_id: None,
+        created_at,
+        in_progress_at: None,
+        expires_at: created_at + COMPLETION_WINDOW_SECS,
+        finalizing_at: None,
+        completed_at: None,
+        failed_at: None,
+        expired_at: None,
+        cancelling_at: None,
+        cancelled_at: None,
+        request_counts: Default::default(),
+        metadata: request.metadata,
+    };
+    let job = Arc::new(Job {
+        owner,
+        batch: Mutex::new(batch.clone()),
+    });
+    store.update(&job, |_| {}).await?;
+    store
+        .batches
+        .write()
+        .unwrap()
+        .insert(batch.id.clone(), job.clone());
+
+    tokio::spawn(run(state.clone(), job));
+    Ok(batch)
+}
+
+/// Run the unfinished batches again, their finished requests are skipped
+pub fn resume(state: &AppState) {
+    let jobs = state
+        .batches()
+        .batches
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    for job in jobs {
+        tokio::spawn(run(state.clone(), job));
+    }
+}
+
+/// Run a batch until every request is done, it is cancelled or it expires
+async fn run(state: AppState, job: Arc<Job>) {
+    let store = state.batches();
+    if let Err(err) = execute(&state, &store, &job).await {
+        let result = store
+            .update(&job, |batch| {
+                tracing::error!("batch {} failed: {err}", batch.id);
+                batch.status = BatchStatus::Failed;
+                batch.failed_at = Some(now());
+                batch.errors = Some(BatchErrors::new(vec![BatchError::new(
+                    "batch_failed",
+                    err.to_string(),
+                    None,
+                    None,
+                )]));
+            })
+            .await;
+        if let Err(err) = result {
+            tracing::error!("Failed to save batch: {err}");
+        }
+    }
+}
+
+async fn execute(state: &AppState, store: &BatchStore, job: &Job) -> crate::Result<()> {
+    let (id, status, input_file_id) = {
+        let batch = job.batch.lock().await;
+        (batch.id.clone(), batch.status, batch.input_file_id.clone())
+    };
+    match status {
+        BatchStatus::Validating | BatchStatus::InProgress | BatchStatus::Cancelling => {}
+        BatchStatus::Finalizing => return finalize(store, job, BatchStatus::Completed).await,
+        _ => return Ok(()),
+    }
+
+    let input = store
+        .file_content(&input_file_id, job.owner.as_deref())
+        .await?;
+    let lines = match parse_lines(&input, store.max_requests) {
+        Ok(lines) => lines,
+        Err(errors) => {
+            store
+                .update(job, |batch| {
+                    batch.status = BatchStatus::Failed;
+                    batch.failed_at = Some(now());
+                    batch.errors = Some(BatchErrors::new(errors));
+                })
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // Results of a previous run are kept, their requests are not sent again
+    let output_path = store.results_path(&id, "output");
+    let error_path = store.results_path(&id, "error");
+    let (succeeded, _) = store.results(&id, "output").await?;
+    let (failed, mut expired) = store.results(&id, "error").await?;
+    let total = lines.len();
+    store
+        .update(job, |batch| {
+            if batch.status == BatchStatus::Validating {
+                batch.status = BatchStatus::InProgress;
+                batch.in_progress_at = Some(now());
+            }
+            batch.request_counts = RequestCounts {
+                total,
+                completed: succeeded.len(),
+                failed: failed.len(),
+            };
+        })
+        .await?;
+
+    let pending = lines
+        .into_iter()
+        .filter(|line| !succeeded.contains(&line.custom_id) && !failed.contains(&line.custom_id));
+    let mut output = append(&output_path).await?;
+    let mut errors = append(&error_path).await?;
+
+    let mut results = stream::iter(pending)
+        .map(|line| execute_line(state, store, job, line))
+        .buffer_unordered(store.concurrency);
+    while let Some(result) = results.next().await {
+        // Skipped once the batch is cancelled
+        let Some(result) = result else {
+            continue;
+        };
+        expired |= result.error.is_some();
+        let success = result
+            .response
+            .as_ref()
+            .map_or(false, |resp| (200..300).contains(&resp.status_code));
+
+        let mut data = serde_json::to_vec(&result)?;
+        data.push(b'\n');
+        let file = if success { &mut output } else { &mut errors };
+        file.write_all(&data).await?;
+        file.flush().await?;
+
+        let mut batch = job.batch.lock().await;
+        if success {
+            batch.request_counts.completed += 1;
+        } else {
+            batch.request_counts.failed += 1;
+        }
+    }
+    drop(results);
+
+    let status = match job.batch.lock().await.status {
+        BatchStatus::Cancelling => BatchStatus::Cancelled,
+        _ if expired => BatchStatus::Expired,
+        _ => BatchStatus::Completed,
+    };
+    finalize(store, job, status).await
+}
+
+/// Send one request, `None` once the batch is cancelled
+async fn execute_line(
+    state: &AppState,
+    store: &BatchStore,
+    job: &Job,
+    line: RequestLine,
+) -> Option<ResultLine> {
+    // The semaphore is never closed
+    let _permit = store.permits.acquire().await.ok()?;
+    let (status, expires_at) = {
+        let batch = job.batch.lock().await;
+        (batch.status, batch.expires_at)
+    };
+    if status == BatchStatus::Cancelling {
+        return None;
+    }
+
+    let (response, error) = if now() >= expires_at {
+        let error = ResultError {
+            code: "batch_expired".to_owned(),
+            message: "This request could not be executed before the completion window expired."
+                .to_owned(),
+        };
+        (None, Some(error))
+    } else {
+        // The owner limits are only taken once the request may run, so that
+        // queued requests do not hold the concurrency slots of the key
+        let resp = match acquire_limits(state, job).await {
+            Ok((runtime, permit)) => {
+                route::batch_chat(&runtime, job.owner.as_deref(), permit, line.body).await
+            }
+            Err(err) => {
+                if job.batch.lock().await.status == BatchStatus::Cancelling {
+                    return None;
+                }
+                err.into_response()
+            }
+        };
+        (Some(ResultResponse::read(resp).await), None)
+    };
+
+    Some(ResultLine {
+        id: random_id("batch_req"),
+        custom_id: line.custom_id,
+        response,
+        error,
+    })
+}
+
+/// Wait for the rate limits of the API key owning the batch, gives up with the
+/// rate limit error once the batch is cancelled or the wait would outlast the
+/// completion window
+async fn acquire_limits(
+    state: &AppState,
+    job: &Job,
+) -> crate::Result<(Arc<Runtime>, Option<Permit>)> {
+    let limiter = state.limiter();
+    loop {
+        let runtime = state.load();
+        let Some(ref owner) = job.owner else {
+            return Ok((runtime, None));
+        };
+        let key = runtime.api_key(owner)?;
+        let err = match limiter.acquire_for_key(runtime.rate_limit(), key).await {
+            Ok(permit) => return Ok((runtime, Some(permit))),
+            Err(err) => err,
+        };
+
+        let (status, expires_at) = {
+            let batch = job.batch.lock().await;
+            (batch.status, batch.expires_at)
+        };
+        match err {
+            Error::RateLimited { reset, .. }
+                if status != BatchStatus::Cancelling && now() + reset.as_secs() < expires_at =>
+            {
+                tokio::time::sleep(reset.min(LIMIT_WAIT)).await
+            }
+            err => return Err(err),
+        }
+    }
+}
+
+/// Publish the output and error files and set the final status
+async fn finalize(store: &BatchStore, job: &Job, status: BatchStatus) -> crate::Result<()> {
+    let id = if status == BatchStatus::Completed {
+        store
+            .update(job, |batch| {
+                batch.status = BatchStatus::Finalizing;
+                batch.finalizing_at.get_or_insert_with(now);
+            })
+            .await?
+            .id
+    } else {
+        job.batch.lock().await.id.clone()
+    };
+
+    let owner = job.owner.as_deref();
+    let output_file_id = store.publish(&id, "output", owner).await?;
+    let error_file_id = store.publish(&id, "error", owner).await?;
+    store
+        .update(job, |batch| {
+            let now = now();
+            batch.status = status;
+            batch.output_file_id = batch.output_file_id.take().or(output_file_id);
+            batch.error_file_id = batch.error_file_id.take().or(error_file_id);
+            match status {
+                BatchStatus::Expired => batch.expired_at = Some(now),
+                BatchStatus::Cancelled => batch.cancelled_at = Some(now),
+                _ => batch.completed_at = Some(now),
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// Requests of the input file, or the errors of every invalid line
+fn parse_lines(input: &[u8], max_requests: usize) -> Result<Vec<RequestLine>, Vec<BatchError>> {
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+    let mut custom_ids = HashSet::new();
+
+    for (index, line) in input.split(|byte| *byte == b'\n').enumerate() {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        let number = Some(index + 1);
+        let error = match serde_json::from_slice::<RequestLine>(line) {
+            Err(err) => BatchError::new(
+                "invalid_json_line",
+                format!("This line is not a valid request: {err}"),
+                None,
+                number,
+            ),
+            Ok(request) if request.method != "POST" => BatchError::new(
+                "invalid_method",
+                "Only POST requests are supported".to_owned(),
+                Some("method"),
+                number,
+            ),
+            Ok(request) if request.url != ENDPOINT => BatchError::new(
+                "invalid_url",
+                format!("Only '{ENDPOINT}' requests are supported"),
+                Some("url"),
+                number,
+            ),
+            Ok(request) if custom_ids.contains(&request.custom_id) => BatchError::new(
+                "duplicate_custom_id",
+                format!(
+                    "The custom_id '{}' is used more than once",
+                    request.custom_id
+                ),
+                Some("custom_id"),
+                number,
+            ),
+            Ok(request) => {
+                custom_ids.insert(request.custom_id.clone());
+                lines.push(request);
+                continue;
+            }
+        };
+        errors.push(error);
+    }
+
+    if lines.is_empty() && errors.is_empty() {
+        errors.push(BatchError::new(
+            "empty_file",
+            "The input file has no requests".to_owned(),
+            None,
+            None,
+        ));
+    }
+    if lines.len() > max_requests {
+        errors.push(BatchError::new(
+            "too_many_requests",
+            format!("A batch may have at most {max_requests} requests"),
+            None,
+            None,
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Custom ids of a results file and whether any request expired, a line cut
+/// short by a restart is dropped
+async fn read_results(path: &Path) -> crate::Result<(HashSet<String>, bool)> {
+    let data = match fs::read(path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((HashSet::new(), false)),
+        Err(err) => return Err(err.into()),
+    };
+
+    let complete = data
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    if complete < data.len() {
+        let file = fs::OpenOptions::new().write(true).open(path).await?;
+        file.set_len(complete as u64).await?;
+    }
+
+    let mut custom_ids = HashSet::new();
+    let mut expired = false;
+    for line in data[..complete].split(|byte| *byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let result = serde_json::from_slice::<ResultLine>(line)?;
+        expired |= result.error.is_some();
+        custom_ids.insert(result.custom_id);
+    }
+    Ok((custom_ids, expired))
+}
+
+/// Id of the `batch_output` file published from the results of a batch
+fn published_id(batch_id: &str, kind: &str) -> String {
+    let id = batch_id.strip_prefix("batch_").unwrap_or(batch_id);
+    format!("file_{id}_{kind}")
+}
+
+/// Length of a file, `None` if it does not exist
+async fn file_len(path: &Path) -> crate::Result<Option<u64>> {
+    match fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Records of the `.json` files of a directory, unreadable ones are skipped
+async fn read_records<T: DeserializeOwned>(dir: &Path) -> crate::Result<Vec<Record<T>>> {
+    let mut records = Vec::new();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(records),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path
+            .extension()
+            .map_or(true, |extension| extension != "json")
+        {
+            continue;
+        }
+
+        let record = fs::read(&path)
+            .await
+            .map_err(Error::from)
+            .and_then(|data| serde_json::from_slice(&data).map_err(Error::from));
+        match record {
+            Ok(record) => records.push(record),
+            Err(err) => tracing::warn!("Skipping {}: {err}", path.display()),
+        }
+    }
+    Ok(records)
+}
+
+/// Replace a record at once, a restart never leaves it half written
+async fn write_record<T: Serialize>(path: &Path, record: &Record<T>) -> crate::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(record)?).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+async fn append(path: &Path) -> crate::Result<fs::File> {
+    Ok(fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+fn invalid_upload(err: MultipartError) -> Error {
+    Error::InvalidParameter("file", err.body_text())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,62 @@
+use crate::{config::ModelConfig, error::Error};
+use std::collections::HashMap;
+
+/// Model catalog, maps public ids and aliases to upstream models
+pub struct ModelCatalog {
+    models: Vec<ModelConfig>,
+    index: HashMap<String, usize>,
+    reject_unknown: bool,
+}
+
+impl ModelCatalog {
+    pub fn new(models: Vec<ModelConfig>, reject_unknown: bool) -> Self {
+        let mut index = HashMap::new();
+        for (i, model) in models.iter().enumerate() {
+            let names = std::iter::once(&model.id)
+                .chain(model.aliases.iter())
+                .chain(std::iter::once(&model.upstream));
+            for name in names {
+                if let Some(prev) = index.insert(name.clone(), i) {
+                    if prev != i {
+                        tracing::warn!(
+                            "model name {name} is declared by both {} and {}",
+                            models[prev].id,
+                            model.id
+                        );
+                    }
+                }
+            }
+        }
+
+        Self {
+            models,
+            index,
+            reject_unknown,
+        }
+    }
+
+    /// Resolve a requested model name to its catalog entry
+    pub fn resolve(&self, model: &str) -> crate::Result<&ModelConfig> {
+        if let Some(index) = self.index.get(model) {
+            return Ok(&self.models[*index]);
+        }
+
+        if self.reject_unknown {
+            return Err(Error::ModelNotFound(model.to_owned()));
+        }
+
+        // Unknown models fall back to the first model of the catalog
+        self.models
+            .first()
+            .ok_or_else(|| Error::ModelNotFound(model.to_owned()))
+    }
+
+    /// Catalog entry of a model name, without falling back
+    pub fn get(&self, model: &str) -> Option<&ModelConfig> {
+        self.index.get(model).map(|index| &self.models[*index])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ModelConfig> {
+        self.models.iter()
+    }
+}
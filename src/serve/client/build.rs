@@ -24,12 +24,20 @@ pub struct HttpConfig {
     // proxy
     #[builder(default, setter(into))]
     proxy_url: Option<Url>,
+
+    // impersonate seed, random impersonate if unset
+    #[builder(default, setter(into))]
+    impersonate_seed: Option<u64>,
 }
 
 impl HttpConfig {
     pub fn set_iface(&mut self, iface: Option<IpAddr>) {
         self.iface = iface;
     }
+
+    pub fn set_impersonate_seed(&mut self, seed: Option<u64>) {
+        self.impersonate_seed = seed;
+    }
 }
 
 /// Build a client
@@ -57,7 +65,7 @@ pub async fn init_builder(config: HttpConfig) -> ClientBuilder {
     // init dns resolver
     set_dns_resolver(builder, lookup_ip_strategy)
         .await
-        .impersonate(random_impersonate())
+        .impersonate(select_impersonate(config.impersonate_seed))
         .cookie_store(true)
         .timeout(Duration::from_secs(config.timeout))
         .connect_timeout(Duration::from_secs(config.connect_timeout))
@@ -105,7 +113,7 @@ async fn set_dns_resolver(builder: ClientBuilder, ip_s: LookupIpStrategy) -> Cli
     builder.dns_resolver(trust_dns_resolver)
 }
 
-fn random_impersonate() -> Impersonate {
+fn select_impersonate(seed: Option<u64>) -> Impersonate {
     static VERSIONS: &'static [Impersonate] = &[
         Impersonate::Chrome100,
         Impersonate::Chrome101,
@@ -161,7 +169,10 @@ fn random_impersonate() -> Impersonate {
         Impersonate::Firefox133,
     ];
 
-    *VERSIONS
-        .choose(&mut rand::thread_rng())
-        .unwrap_or(&Impersonate::default())
+    match seed {
+        Some(seed) => VERSIONS[(seed % VERSIONS.len() as u64) as usize],
+        None => *VERSIONS
+            .choose(&mut rand::thread_rng())
+            .unwrap_or(&Impersonate::default()),
+    }
 }
@@ -0,0 +1,224 @@
+use super::{
+    build::{self, HttpConfig},
+    pool::{Member, PoolClient},
+};
+use crate::config::{CidrConfig, Config, Rotation};
+use cidr::IpCidr;
+use moka::future::Cache;
+use rand::Rng;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// CIDR pool member, clients are built per source address and cached
+pub struct CidrMember {
+    cidr: IpCidr,
+    weight: u32,
+    in_flight: Arc<AtomicUsize>,
+
+    /// When the current source address is replaced
+    rotation: Rotation,
+
+    /// Number of clients built ahead of rotation
+    prebuild: usize,
+
+    config: HttpConfig,
+    conf: Arc<Config>,
+
+    current: Mutex<Option<Current>>,
+    clients: Cache<IpAddr, Arc<PoolClient>>,
+    spare: Mutex<Vec<(IpAddr, Arc<PoolClient>)>>,
+    refilling: AtomicBool,
+}
+
+/// Source address currently in use
+struct Current {
+    addr: IpAddr,
+    uses: u64,
+    since: Instant,
+}
+
+impl CidrMember {
+    pub fn new(
+        cidr: IpCidr,
+        weight: u32,
+        cidr_conf: &CidrConfig,
+        config: HttpConfig,
+        conf: Arc<Config>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            cidr,
+            weight,
+            in_flight: Default::default(),
+            rotation: cidr_conf.rotation,
+            prebuild: cidr_conf.prebuild,
+            config,
+            conf,
+            current: Mutex::new(None),
+            clients: Cache::builder().max_capacity(cidr_conf.cache_size).build(),
+            spare: Mutex::new(Vec::new()),
+            refilling: AtomicBool::new(false),
+        })
+    }
+
+    /// Load the client of the current source address, rotating it when due
+    pub async fn load_client(self: &Arc<Self>) -> Arc<PoolClient> {
+        let client = match self.current_addr() {
+            Some(addr) => self.client_for(addr).await,
+            None => {
+                let (addr, client) = match self.take_spare() {
+                    Some(spare) => spare,
+                    None => {
+                        self.build(random_addr(self.cidr), self.reuse_tokens())
+                            .await
+                    }
+                };
+
+                *self.current.lock().unwrap() = Some(Current {
+                    addr,
+                    uses: 1,
+                    since: Instant::now(),
+                });
+                self.clients.insert(addr, client.clone()).await;
+                client
+            }
+        };
+
+        self.prebuild_clients();
+        client
+    }
+
+    /// Load the client bound to `addr`, building it if it is not cached
+    pub async fn client_for(&self, addr: IpAddr) -> Arc<PoolClient> {
+        self.clients
+            .get_with(addr, async move { self.build(addr, true).await.1 })
+            .await
+    }
+
+    /// Load a client bound to a new random source address, kept out of rotation
+    pub async fn pin_client(&self) -> (IpAddr, Arc<PoolClient>) {
+        let addr = random_addr(self.cidr);
+        (addr, self.client_for(addr).await)
+    }
+
+    /// Cached clients
+    pub fn clients(&self) -> Vec<Arc<PoolClient>> {
+        self.clients.iter().map(|(_, client)| client).collect()
+    }
+
+    /// Reuse the current source address unless its rotation is due
+    fn current_addr(&self) -> Option<IpAddr> {
+        let mut current = self.current.lock().unwrap();
+        let current = current.as_mut()?;
+        let due = match self.rotation {
+            Rotation::Request => true,
+            Rotation::Requests(n) => current.uses >= n,
+            Rotation::Interval(secs) => current.since.elapsed() >= Duration::from_secs(secs),
+        };
+
+        if due {
+            return None;
+        }
+
+        current.uses += 1;
+        Some(current.addr)
+    }
+
+    /// Whether clients of the rotating source address serve more than one
+    /// request, tokens of a client rotated out after a single request would
+    /// never be used
+    fn reuse_tokens(&self) -> bool {
+        !matches!(self.rotation, Rotation::Request)
+    }
+
+    fn take_spare(&self) -> Option<(IpAddr, Arc<PoolClient>)> {
+        self.spare.lock().unwrap().pop()
+    }
+
+    /// Build clients ahead of rotation in the background, each with a token
+    /// ready for its first request
+    fn prebuild_clients(self: &Arc<Self>) {
+        if self.spare.lock().unwrap().len() >= self.prebuild
+            || self
+                .refilling
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            while this.spare.lock().unwrap().len() < this.prebuild {
+                let spare = this
+                    .build(random_addr(this.cidr), this.reuse_tokens())
+                    .await;
+                spare.1.probe().await;
+                this.spare.lock().unwrap().push(spare);
+            }
+            this.refilling.store(false, Ordering::Release);
+        });
+    }
+
+    async fn build(&self, addr: IpAddr, reuse_tokens: bool) -> (IpAddr, Arc<PoolClient>) {
+        let mut config = self.config.clone();
+        config.set_iface(Some(addr));
+        // Derive the impersonation from the address, so that a rebuilt client
+        // keeps the same identity
+        config.set_impersonate_seed(Some(seed(addr)));
+        let client = build::build_client(config).await;
+        let client = PoolClient::new(
+            addr.to_string(),
+            client,
+            self.weight,
+            self.in_flight.clone(),
+            reuse_tokens,
+            &self.conf,
+        );
+        (addr, client)
+    }
+}
+
+impl Member for CidrMember {
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+fn seed(addr: IpAddr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    addr.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Generate a random address inside the CIDR range
+fn random_addr(cidr: IpCidr) -> IpAddr {
+    match cidr.first_address() {
+        IpAddr::V4(v4) => {
+            let v4 = u32::from(v4);
+            let prefix_len = cidr.network_length();
+            let rand: u32 = rand::thread_rng().gen();
+            let net_part = (v4 >> (32 - prefix_len)) << (32 - prefix_len);
+            let host_part = (rand << prefix_len) >> prefix_len;
+            IpAddr::V4((net_part | host_part).into())
+        }
+        IpAddr::V6(v6) => {
+            let ipv6 = u128::from(v6);
+            let prefix_len = cidr.network_length();
+            let rand: u128 = rand::thread_rng().gen();
+            let net_part = (ipv6 >> (128 - prefix_len)) << (128 - prefix_len);
+            let host_part = (rand << prefix_len) >> prefix_len;
+            IpAddr::V6((net_part | host_part).into())
+        }
+    }
+}
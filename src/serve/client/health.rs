@@ -0,0 +1,122 @@
+use crate::config::HealthConfig;
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Health state of a pooled client
+pub struct Health {
+    /// Consecutive failures before the client is ejected
+    max_failures: u32,
+
+    /// Initial ejection time, doubled on each consecutive ejection
+    ejection: Duration,
+
+    /// Maximum ejection time
+    max_ejection: Duration,
+
+    failures: AtomicU32,
+
+    /// Failures since the client was built
+    failures_total: AtomicU64,
+
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Healthy { since: Instant, ejections: u32 },
+    Ejected { until: Instant, ejections: u32 },
+}
+
+impl Health {
+    pub fn new(conf: &HealthConfig) -> Self {
+        Self {
+            max_failures: conf.max_failures.max(1),
+            ejection: Duration::from_secs(conf.ejection),
+            max_ejection: Duration::from_secs(conf.max_ejection),
+            failures: AtomicU32::new(0),
+            failures_total: AtomicU64::new(0),
+            state: Mutex::new(State::Healthy {
+                since: Instant::now(),
+                ejections: 0,
+            }),
+        }
+    }
+
+    /// Whether the client may receive traffic, ejected clients become
+    /// available again once their ejection time has elapsed
+    pub fn is_available(&self) -> bool {
+        match *self.state.lock().unwrap() {
+            State::Healthy { .. } => true,
+            State::Ejected { until, .. } => Instant::now() >= until,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), State::Healthy { .. })
+    }
+
+    /// Consecutive failures, reset by a success
+    pub fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn failures_total(&self) -> u64 {
+        self.failures_total.load(Ordering::Relaxed)
+    }
+
+    pub fn record_success(&self, name: &str) {
+        self.failures.store(0, Ordering::Relaxed);
+
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            // A request sent before the ejection does not cut the ejection short,
+            // the client is only re-admitted once its ejection time has elapsed
+            State::Ejected { until, .. } if Instant::now() < until => {}
+            State::Ejected { ejections, .. } => {
+                tracing::info!("{name}: re-admitted to the pool");
+                *state = State::Healthy {
+                    since: Instant::now(),
+                    ejections,
+                };
+            }
+            // A client that stays healthy long enough forgets its previous ejections
+            State::Healthy { since, ejections }
+                if ejections > 0 && since.elapsed() >= self.max_ejection =>
+            {
+                *state = State::Healthy {
+                    since,
+                    ejections: 0,
+                };
+            }
+            State::Healthy { .. } => {}
+        }
+    }
+
+    pub fn record_failure(&self, name: &str) {
+        self.failures_total.fetch_add(1, Ordering::Relaxed);
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let mut state = self.state.lock().unwrap();
+        let ejections = match *state {
+            State::Healthy { ejections, .. } if failures >= self.max_failures => ejections,
+            // A failed trial after the ejection time extends the ejection
+            State::Ejected { until, ejections } if Instant::now() >= until => ejections,
+            _ => return,
+        };
+
+        let factor = 1u32 << ejections.min(16);
+        let duration = self.ejection.saturating_mul(factor).min(self.max_ejection);
+        tracing::warn!(
+            "{name}: ejected from the pool for {duration:?} after {failures} consecutive failures"
+        );
+        *state = State::Ejected {
+            until: Instant::now() + duration,
+            ejections: ejections.saturating_add(1),
+        };
+    }
+}
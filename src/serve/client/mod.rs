@@ -1,14 +1,25 @@
 mod build;
+mod cidr;
 mod dns;
+mod health;
 mod pool;
 #[cfg(target_os = "linux")]
 mod route;
+mod token;
 
 use crate::config;
 use pool::Pool;
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
-/// Client round-robin balancer
+pub use pool::{InFlight, PoolClient, PoolClientStats};
+
+pub const ORIGIN_API: &str = "https://duckduckgo.com";
+
+/// Client load balancer
 #[derive(Clone)]
 pub struct ClientLoadBalancer {
     pool: Arc<Pool>,
@@ -16,10 +27,30 @@ pub struct ClientLoadBalancer {
 }
 
 impl ClientLoadBalancer {
-    pub async fn new(conf: config::Config) -> Self {
-        Self {
-            pool: Arc::new(Pool::new(conf).await),
-            _priv: (),
+    pub async fn new(conf: config::Config) -> crate::Result<Self> {
+        let probe_interval = conf.health.probe_interval;
+        let pool = Arc::new(Pool::new(conf).await?);
+
+        if probe_interval > 0 {
+            tokio::spawn(probe_task(
+                Arc::downgrade(&pool),
+                Duration::from_secs(probe_interval),
+            ));
+        }
+
+        Ok(Self { pool, _priv: () })
+    }
+}
+
+/// Periodically probe the pool clients until the pool is dropped
+async fn probe_task(pool: Weak<Pool>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match pool.upgrade() {
+            Some(pool) => pool.probe().await,
+            None => break,
         }
     }
 }
@@ -1,170 +1,572 @@
-use super::build::{self, HttpConfig};
-use crate::{config::Config, proxy::Proxies};
-use cidr::IpCidr;
+use super::{
+    build::{self, HttpConfig},
+    cidr::CidrMember,
+    health::Health,
+    token::{self, TokenPool, TokenStats},
+};
+use crate::{
+    config::{BalanceStrategy, Config},
+    error::Error,
+    proxy::Proxies,
+};
+use futures_util::future::join_all;
+use moka::future::Cache;
 use rand::Rng;
 use rquest::Client;
+use serde::Serialize;
 use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
     net::IpAddr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-pub enum Pool {
-    Default(Client),
-    Ifaces {
-        load_factor: AtomicUsize,
-        clients: Vec<Client>,
-    },
-    Proxy {
-        load_factor: AtomicUsize,
-        clients: Vec<Client>,
-    },
-    CIDR {
-        load_factor: AtomicUsize,
-        config: HttpConfig,
-        cidr: Vec<IpCidr>,
-    },
+/// Proxy URL schemes supported by the client
+const PROXY_SCHEMES: &[&str] = &["http", "https", "socks4", "socks4a", "socks5", "socks5h"];
+
+/// Pooled client with its own `x-vqd-4` token pool and health state
+pub struct PoolClient {
+    name: String,
+    client: Client,
+    weight: u32,
+    in_flight: Arc<AtomicUsize>,
+    tokens: TokenPool,
+    health: Health,
+
+    /// Whether tokens are kept for later requests, clients rotated out after
+    /// a single request neither prefetch nor reuse tokens
+    reuse_tokens: bool,
 }
 
-impl Pool {
-    pub async fn new(conf: Config) -> Self {
-        // split proxy
-        let (proxies, ifaces, cidr): (Vec<_>, Vec<_>, Vec<_>) = conf.proxies.into_iter().fold(
-            (vec![], vec![], vec![]),
-            |(mut proxies, mut interfaces, mut cidr), proxy| {
-                match proxy {
-                    Proxies::URL(v) => proxies.push(v),
-                    Proxies::Iface(v) => interfaces.push(v),
-                    Proxies::CIDR(v) => cidr.push(v),
-                }
-                (proxies, interfaces, cidr)
-            },
-        );
-
-        #[cfg(target_os = "linux")]
-        for ip_cidr in cidr.iter() {
-            {
-                super::route::sysctl_ipv6_no_local_bind();
-                super::route::sysctl_route_add_cidr(&ip_cidr).await;
+/// Token pool counters and health state of a pooled client
+#[derive(Serialize)]
+pub struct PoolClientStats {
+    pub name: String,
+    pub weight: u32,
+    pub in_flight: usize,
+    pub healthy: bool,
+    pub failures: u32,
+    pub failures_total: u64,
+    pub tokens: TokenStats,
+}
+
+impl PoolClient {
+    pub(super) fn new(
+        name: String,
+        client: Client,
+        weight: u32,
+        in_flight: Arc<AtomicUsize>,
+        reuse_tokens: bool,
+        conf: &Config,
+    ) -> Arc<Self> {
+        let tokens = TokenPool::builder()
+            .prefetch(conf.token_prefetch)
+            .ttl(Duration::from_secs(conf.token_ttl))
+            .build();
+
+        Arc::new(Self {
+            name,
+            client,
+            weight,
+            in_flight,
+            tokens,
+            health: Health::new(&conf.health),
+            reuse_tokens,
+        })
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Take a prefetched token or fetch a new one, then refill in the background
+    pub async fn acquire_token(self: &Arc<Self>) -> crate::Result<String> {
+        let token = match self.tokens.pop() {
+            Some(token) => token,
+            None => self.fetch_token().await?,
+        };
+
+        if self.reuse_tokens {
+            self.prefetch_token();
+        }
+        Ok(token)
+    }
+
+    /// Fetch a new token, bypassing the stored tokens
+    pub async fn fetch_token(&self) -> crate::Result<String> {
+        let token = token::fetch_token(&self.client).await?;
+        self.tokens.record_fetch();
+        Ok(token)
+    }
+
+    /// Reuse the rotated token returned by a chat response
+    #[inline]
+    pub fn release_token(&self, token: String) {
+        if self.reuse_tokens {
+            self.tokens.push_rotated(token);
+        }
+    }
+
+    /// Drop all stored tokens after an upstream rejection
+    #[inline]
+    pub fn invalidate_tokens(&self) {
+        self.tokens.invalidate();
+    }
+
+    /// Count a request as in flight until the returned guard is dropped
+    pub fn begin_request(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.in_flight.clone())
+    }
+
+    /// Whether the client may receive traffic
+    #[inline]
+    pub fn is_available(&self) -> bool {
+        self.health.is_available()
+    }
+
+    #[inline]
+    pub fn record_success(&self) {
+        self.health.record_success(&self.name);
+    }
+
+    #[inline]
+    pub fn record_failure(&self) {
+        self.health.record_failure(&self.name);
+    }
+
+    /// Probe the status endpoint, the fetched token is kept for later use
+    pub async fn probe(&self) {
+        // Ejected clients are only probed once their ejection time has elapsed
+        if !self.health.is_available() {
+            return;
+        }
+
+        match token::fetch_token(&self.client).await {
+            Ok(token) => {
+                self.tokens.push_fetched(token);
+                self.record_success();
             }
+            Err(err) => {
+                tracing::debug!("{}: probe failed: {err}", self.name);
+                self.record_failure();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolClientStats {
+        PoolClientStats {
+            name: self.name.clone(),
+            weight: self.weight,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            healthy: self.health.is_healthy(),
+            failures: self.health.failures(),
+            failures_total: self.health.failures_total(),
+            tokens: self.tokens.stats(),
         }
+    }
 
-        // Priority: cidr > proxies > ifaces
-        match (!cidr.is_empty(), !proxies.is_empty(), !ifaces.is_empty()) {
-            (true, _, _) => {
-                let config = HttpConfig::builder()
-                    .timeout(conf.timeout)
-                    .connect_timeout(conf.connect_timeout)
-                    .tcp_keepalive(conf.tcp_keepalive)
-                    .build();
+    fn prefetch_token(self: &Arc<Self>) {
+        if !self.tokens.begin_refill() {
+            return;
+        }
 
-                Pool::CIDR {
-                    config,
-                    load_factor: AtomicUsize::new(0),
-                    cidr,
+        let this = self.clone();
+        tokio::spawn(async move {
+            while this.tokens.needs_refill() {
+                match token::fetch_token(&this.client).await {
+                    Ok(token) => this.tokens.push_fetched(token),
+                    Err(err) => {
+                        tracing::debug!("{}: failed to prefetch x-vqd-4 token: {err}", this.name);
+                        break;
+                    }
                 }
             }
-            (false, true, _) => {
-                let mut clients = vec![];
+            this.tokens.end_refill();
+        });
+    }
+}
+
+/// In-flight request guard, released when dropped
+pub struct InFlight(Arc<AtomicUsize>);
 
-                for proxy_url in proxies {
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Pool member, any kind of [`Proxies`]
+pub enum PoolMember {
+    /// Client bound to a proxy URL or an interface address
+    Client(Arc<PoolClient>),
+    /// CIDR range, source addresses are picked at random and rotated
+    CIDR(Arc<CidrMember>),
+}
+
+impl PoolMember {
+    fn is_available(&self) -> bool {
+        match self {
+            PoolMember::Client(client) => client.is_available(),
+            PoolMember::CIDR(_) => true,
+        }
+    }
+
+    fn as_member(&self) -> &dyn Member {
+        match self {
+            PoolMember::Client(client) => client.as_ref(),
+            PoolMember::CIDR(cidr) => cidr.as_ref(),
+        }
+    }
+}
+
+/// Pinned upstream identity of a sticky session
+#[derive(Clone, Copy)]
+enum Pin {
+    /// Index of a client member
+    Client(usize),
+    /// Index of a CIDR member and the pinned source address
+    CIDR(usize, IpAddr),
+}
+
+/// Unified client pool mixing proxy URLs, interfaces and CIDR ranges
+pub struct Pool {
+    balance: Box<dyn Balance>,
+    members: Vec<PoolMember>,
+    pins: Option<Cache<u64, Pin>>,
+}
+
+impl Pool {
+    pub async fn new(mut conf: Config) -> crate::Result<Self> {
+        let config = HttpConfig::builder()
+            .timeout(conf.timeout)
+            .connect_timeout(conf.connect_timeout)
+            .tcp_keepalive(conf.tcp_keepalive)
+            .build();
+
+        let proxies = std::mem::take(&mut conf.proxies);
+        let conf = Arc::new(conf);
+        let mut members = Vec::with_capacity(proxies.len());
+        let mut seen = HashSet::new();
+        for proxy in proxies {
+            if !seen.insert(format!("{proxy:?}")) {
+                tracing::warn!("Proxy {proxy:?} is declared more than once, ignoring duplicate");
+                continue;
+            }
+
+            let member = match proxy {
+                Proxies::URL(proxy_url) => {
+                    if !PROXY_SCHEMES.contains(&proxy_url.scheme()) {
+                        return Err(Error::InvalidProxy(proxy_url.value.to_string()));
+                    }
+
+                    let name = proxy_url.value.to_string();
                     let config = HttpConfig::builder()
                         .timeout(conf.timeout)
                         .connect_timeout(conf.connect_timeout)
                         .tcp_keepalive(conf.tcp_keepalive)
-                        .proxy_url(proxy_url)
+                        .proxy_url(proxy_url.value)
                         .build();
 
                     let client = build::build_client(config).await;
-                    clients.push(client);
-                }
-
-                Pool::Proxy {
-                    load_factor: AtomicUsize::new(0),
-                    clients,
+                    PoolMember::Client(PoolClient::new(
+                        name,
+                        client,
+                        proxy_url.weight,
+                        Default::default(),
+                        true,
+                        &conf,
+                    ))
                 }
-            }
-            (false, false, true) => {
-                let mut clients = vec![];
-
-                for iface in ifaces {
+                Proxies::Iface(iface) => {
                     let config = HttpConfig::builder()
                         .timeout(conf.timeout)
                         .connect_timeout(conf.connect_timeout)
                         .tcp_keepalive(conf.tcp_keepalive)
-                        .iface(iface)
+                        .iface(iface.value)
                         .build();
 
                     let client = build::build_client(config).await;
-                    clients.push(client);
+                    PoolMember::Client(PoolClient::new(
+                        iface.value.to_string(),
+                        client,
+                        iface.weight,
+                        Default::default(),
+                        true,
+                        &conf,
+                    ))
                 }
+                Proxies::CIDR(cidr) => {
+                    #[cfg(target_os = "linux")]
+                    {
+                        super::route::sysctl_ipv6_no_local_bind();
+                        super::route::sysctl_route_add_cidr(&cidr.value).await;
+                    }
 
-                Pool::Ifaces {
-                    load_factor: AtomicUsize::new(0),
-                    clients,
+                    PoolMember::CIDR(CidrMember::new(
+                        cidr.value,
+                        cidr.weight,
+                        &conf.cidr,
+                        config.clone(),
+                        conf.clone(),
+                    ))
                 }
-            }
-            _ => {
-                let config = HttpConfig::builder()
-                    .timeout(conf.timeout)
-                    .connect_timeout(conf.connect_timeout)
-                    .tcp_keepalive(conf.tcp_keepalive)
-                    .build();
-
-                Pool::Default(build::build_client(config).await)
-            }
+            };
+            members.push(member);
         }
+
+        // No proxy configured, use a direct client
+        if members.is_empty() {
+            let client = build::build_client(config.clone()).await;
+            members.push(PoolMember::Client(PoolClient::new(
+                "default".to_owned(),
+                client,
+                1,
+                Default::default(),
+                true,
+                &conf,
+            )));
+        }
+
+        // Sticky session pins, refreshed on every request
+        let pins = conf.affinity.source.map(|_| {
+            Cache::builder()
+                .max_capacity(conf.affinity.capacity)
+                .time_to_idle(Duration::from_secs(conf.affinity.ttl))
+                .build()
+        });
+
+        Ok(Pool {
+            balance: new_balance(conf.balance),
+            members,
+            pins,
+        })
     }
 
     #[inline]
-    pub async fn load_client(&self) -> Client {
-        match self {
-            Pool::Default(client) => client.clone(),
-            Pool::Ifaces {
-                clients,
-                load_factor,
-            } => {
-                let index = round_robin_factor(clients.len(), load_factor);
-                clients[index].clone()
+    pub async fn load_client(&self) -> Arc<PoolClient> {
+        match &self.members[self.select()] {
+            PoolMember::Client(client) => client.clone(),
+            PoolMember::CIDR(member) => member.load_client().await,
+        }
+    }
+
+    /// Load the client pinned to the affinity key, pinning a newly selected
+    /// client when there is no pin yet or the pinned one is unavailable
+    pub async fn load_client_with(&self, affinity: Option<&str>) -> Arc<PoolClient> {
+        let (Some(pins), Some(affinity)) = (self.pins.as_ref(), affinity) else {
+            return self.load_client().await;
+        };
+
+        let key = {
+            let mut hasher = DefaultHasher::new();
+            affinity.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        if let Some(pin) = pins.get(&key).await {
+            let client = match pin {
+                Pin::Client(index) => match &self.members[index] {
+                    PoolMember::Client(client) => Some(client.clone()),
+                    PoolMember::CIDR(_) => None,
+                },
+                Pin::CIDR(index, addr) => match &self.members[index] {
+                    PoolMember::CIDR(member) => Some(member.client_for(addr).await),
+                    PoolMember::Client(_) => None,
+                },
+            };
+
+            match client {
+                Some(client) if client.is_available() => return client,
+                Some(client) => {
+                    tracing::debug!(
+                        "{}: pinned client is unavailable, re-pinning",
+                        client.name()
+                    )
+                }
+                None => {}
             }
-            Pool::Proxy {
-                clients,
-                load_factor,
-            } => {
-                let index = round_robin_factor(clients.len(), load_factor);
-                clients[index].clone()
+        }
+
+        let index = self.select();
+        let (pin, client) = match &self.members[index] {
+            PoolMember::Client(client) => (Pin::Client(index), client.clone()),
+            PoolMember::CIDR(member) => {
+                // Sessions get their own source address instead of the rotating one
+                let (addr, client) = member.pin_client().await;
+                (Pin::CIDR(index, addr), client)
             }
-            Pool::CIDR {
-                load_factor,
-                config,
-                cidr,
-            } => {
-                let index = round_robin_factor(cidr.len(), load_factor);
-                let cidr = cidr[index];
-
-                let addr = match cidr.first_address() {
-                    IpAddr::V4(v4) => {
-                        let v4 = u32::from(v4);
-                        let prefix_len = cidr.network_length();
-                        let rand: u32 = rand::thread_rng().gen();
-                        let net_part = (v4 >> (32 - prefix_len)) << (32 - prefix_len);
-                        let host_part = (rand << prefix_len) >> prefix_len;
-                        Some(IpAddr::V4((net_part | host_part).into()))
-                    }
-                    IpAddr::V6(v6) => {
-                        let ipv6 = u128::from(v6);
-                        let prefix_len = cidr.network_length();
-                        let rand: u128 = rand::thread_rng().gen();
-                        let net_part = (ipv6 >> (128 - prefix_len)) << (128 - prefix_len);
-                        let host_part = (rand << prefix_len) >> prefix_len;
-                        Some(IpAddr::V6((net_part | host_part).into()))
-                    }
-                };
+        };
+
+        pins.insert(key, pin).await;
+        client
+    }
+
+    /// Probe every pooled client
+    pub async fn probe(&self) {
+        join_all(self.clients().map(|client| client.probe())).await;
+    }
 
-                let mut config = config.clone();
-                config.set_iface(addr);
-                build::build_client(config).await
+    /// Token pool counters of every pooled client, including cached CIDR clients
+    pub fn stats(&self) -> Vec<PoolClientStats> {
+        self.members
+            .iter()
+            .flat_map(|member| match member {
+                PoolMember::Client(client) => vec![client.stats()],
+                PoolMember::CIDR(cidr) => {
+                    cidr.clients().iter().map(|client| client.stats()).collect()
+                }
+            })
+            .collect()
+    }
+
+    fn clients(&self) -> impl Iterator<Item = &Arc<PoolClient>> {
+        self.members.iter().filter_map(|member| match member {
+            PoolMember::Client(client) => Some(client),
+            PoolMember::CIDR(_) => None,
+        })
+    }
+
+    /// Select the index of an available member, skipping ejected ones.
+    /// Falls back to all members when every member is ejected.
+    fn select(&self) -> usize {
+        let mut candidates = (0..self.members.len())
+            .filter(|index| self.members[*index].is_available())
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = (0..self.members.len()).collect();
+        }
+
+        let members = candidates
+            .iter()
+            .map(|index| self.members[*index].as_member())
+            .collect::<Vec<_>>();
+        candidates[self.balance.select(&members)]
+    }
+}
+
+/// Pool member seen by a balance strategy
+pub trait Member {
+    /// Balance weight
+    fn weight(&self) -> u32;
+
+    /// Number of requests in flight
+    fn in_flight(&self) -> usize;
+}
+
+impl Member for PoolClient {
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// Pool member selection strategy
+pub trait Balance: Send + Sync {
+    /// Pick the index of the next member, `members` is never empty
+    fn select(&self, members: &[&dyn Member]) -> usize;
+}
+
+/// Cycle through the members in order
+#[derive(Default)]
+pub struct RoundRobin(AtomicUsize);
+
+impl Balance for RoundRobin {
+    fn select(&self, members: &[&dyn Member]) -> usize {
+        round_robin_factor(members.len(), &self.0)
+    }
+}
+
+/// Pick a member uniformly at random
+pub struct Random;
+
+impl Balance for Random {
+    fn select(&self, members: &[&dyn Member]) -> usize {
+        rand::thread_rng().gen_range(0..members.len())
+    }
+}
+
+/// Pick a member at random, proportionally to its weight
+pub struct Weighted;
+
+impl Balance for Weighted {
+    fn select(&self, members: &[&dyn Member]) -> usize {
+        let total = members.iter().map(|m| m.weight() as u64).sum::<u64>();
+        if total == 0 {
+            return Random.select(members);
+        }
+
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for (index, member) in members.iter().enumerate() {
+            let weight = member.weight() as u64;
+            if pick < weight {
+                return index;
             }
+            pick -= weight;
         }
+        members.len() - 1
+    }
+}
+
+/// Pick the member with the fewest requests in flight, ties are broken at random
+pub struct LeastInFlight;
+
+impl Balance for LeastInFlight {
+    fn select(&self, members: &[&dyn Member]) -> usize {
+        let len = members.len();
+        let start = rand::thread_rng().gen_range(0..len);
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .min_by_key(|index| members[*index].in_flight())
+            .unwrap_or(start)
+    }
+}
+
+/// Pick two members at random and keep the one with fewer requests in flight
+pub struct PowerOfTwo;
+
+impl Balance for PowerOfTwo {
+    fn select(&self, members: &[&dyn Member]) -> usize {
+        let len = members.len();
+        if len == 1 {
+            return 0;
+        }
+
+        let mut rng = rand::thread_rng();
+        let a = rng.gen_range(0..len);
+        let mut b = rng.gen_range(0..len - 1);
+        if b >= a {
+            b += 1;
+        }
+
+        if members[b].in_flight() < members[a].in_flight() {
+            b
+        } else {
+            a
+        }
+    }
+}
+
+fn new_balance(strategy: BalanceStrategy) -> Box<dyn Balance> {
+    match strategy {
+        BalanceStrategy::RoundRobin => Box::new(RoundRobin::default()),
+        BalanceStrategy::Random => Box::new(Random),
+        BalanceStrategy::Weighted => Box::new(Weighted),
+        BalanceStrategy::LeastInFlight => Box::new(LeastInFlight),
+        BalanceStrategy::PowerOfTwo => Box::new(PowerOfTwo),
     }
 }
 
@@ -0,0 +1,161 @@
+use super::ORIGIN_API;
+use rquest::{header, Client};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use typed_builder::TypedBuilder;
+
+const STATUS_API: &str = "https://duckduckgo.com/duckchat/v1/status";
+
+/// Fetch a new `x-vqd-4` token from the status endpoint
+pub async fn fetch_token(client: &Client) -> crate::Result<String> {
+    let token = request_token(client).await;
+    crate::serve::metrics::token_fetch(token.is_ok());
+    token
+}
+
+async fn request_token(client: &Client) -> crate::Result<String> {
+    let resp = client
+        .get(STATUS_API)
+        .header(header::REFERER, ORIGIN_API)
+        .header("x-vqd-accept", "1")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let token = resp
+        .headers()
+        .get("x-vqd-4")
+        .and_then(|header| header.to_str().ok())
+        .ok_or_else(|| crate::Error::MissingHeader)?;
+
+    Ok(token.to_string())
+}
+
+/// `x-vqd-4` token pool of a single client
+#[derive(TypedBuilder)]
+pub struct TokenPool {
+    /// Number of tokens kept ready
+    prefetch: usize,
+
+    /// Token time to live
+    ttl: Duration,
+
+    #[builder(default)]
+    tokens: Mutex<VecDeque<(String, Instant)>>,
+
+    #[builder(default)]
+    refilling: AtomicBool,
+
+    #[builder(default)]
+    counters: TokenCounters,
+}
+
+#[derive(Default)]
+struct TokenCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    fetches: AtomicU64,
+    reused: AtomicU64,
+    expired: AtomicU64,
+    invalidated: AtomicU64,
+}
+
+/// Token pool counters snapshot
+#[derive(Serialize)]
+pub struct TokenStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub fetches: u64,
+    pub reused: u64,
+    pub expired: u64,
+    pub invalidated: u64,
+    pub available: usize,
+}
+
+impl TokenPool {
+    /// Take a live token, counting a hit or a miss
+    pub fn pop(&self) -> Option<String> {
+        let mut tokens = self.tokens.lock().unwrap();
+        while let Some((token, fetched)) = tokens.pop_front() {
+            if fetched.elapsed() < self.ttl {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return Some(token);
+            }
+            self.counters.expired.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    /// Store a token fetched from the status endpoint
+    pub fn push_fetched(&self, token: String) {
+        self.counters.fetches.fetch_add(1, Ordering::Relaxed);
+        self.push(token);
+    }
+
+    /// Store a rotated token returned by a chat response
+    pub fn push_rotated(&self, token: String) {
+        self.counters.reused.fetch_add(1, Ordering::Relaxed);
+        self.push(token);
+    }
+
+    /// Count a token fetched on demand
+    pub fn record_fetch(&self) {
+        self.counters.fetches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Drop all stored tokens after an upstream rejection
+    pub fn invalidate(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+        self.counters
+            .invalidated
+            .fetch_add(tokens.len() as u64 + 1, Ordering::Relaxed);
+        tokens.clear();
+    }
+
+    /// Whether fewer than `prefetch` tokens are stored
+    pub fn needs_refill(&self) -> bool {
+        self.tokens.lock().unwrap().len() < self.prefetch
+    }
+
+    /// Mark the pool as refilling, returns `false` if a refill is already running
+    pub fn begin_refill(&self) -> bool {
+        self.needs_refill()
+            && self
+                .refilling
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    pub fn end_refill(&self) {
+        self.refilling.store(false, Ordering::Release);
+    }
+
+    pub fn stats(&self) -> TokenStats {
+        TokenStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            fetches: self.counters.fetches.load(Ordering::Relaxed),
+            reused: self.counters.reused.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+            invalidated: self.counters.invalidated.load(Ordering::Relaxed),
+            available: self.tokens.lock().unwrap().len(),
+        }
+    }
+
+    fn push(&self, token: String) {
+        let mut tokens = self.tokens.lock().unwrap();
+        // Keep at most twice the prefetch size, dropping the oldest tokens
+        while tokens.len() >= self.prefetch.max(1) * 2 {
+            tokens.pop_front();
+        }
+        tokens.push_back((token, Instant::now()));
+    }
+}
@@ -0,0 +1,111 @@
+//! `response_format` emulated on top of plain chat turns.
+//!
+//! The expected JSON, and its schema if any, is described in a system prompt.
+//! The JSON is then extracted from the reply and validated, an invalid reply
+//! is sent back to the model with the validation error so that it can fix it.
+
+use super::{model::ResponseFormat, tools};
+use crate::error::Error;
+use jsonschema::Validator;
+use serde_json::Value;
+
+/// JSON reply expected by `json_object` and `json_schema`
+pub struct JsonFormat {
+    schema: Option<(String, Value)>,
+    validator: Option<Validator>,
+}
+
+impl JsonFormat {
+    /// `None` for plain text replies, fails if the schema does not compile
+    pub fn new(format: ResponseFormat) -> crate::Result<Option<Self>> {
+        match format {
+            ResponseFormat::Text => Ok(None),
+            ResponseFormat::JsonObject => Ok(Some(Self {
+                schema: None,
+                validator: None,
+            })),
+            ResponseFormat::JsonSchema { json_schema } => {
+                let schema = json_schema.schema.unwrap_or_else(|| serde_json::json!({}));
+                let validator = jsonschema::validator_for(&schema).map_err(|err| {
+                    Error::InvalidParameter(
+                        "response_format",
+                        format!(
+                            "Invalid schema for response_format '{}': {err}",
+                            json_schema.name
+                        ),
+                    )
+                })?;
+                Ok(Some(Self {
+                    schema: Some((json_schema.name, schema)),
+                    validator: Some(validator),
+                }))
+            }
+        }
+    }
+
+    /// System prompt describing the expected reply
+    pub fn prompt(&self) -> String {
+        match self.schema {
+            Some((ref name, ref schema)) => format!(
+                "Reply with a single JSON value named `{name}` that matches the following \
+                 JSON schema, and nothing else, no explanation and no code fence:\n\
+                 <schema>\n{schema}\n</schema>"
+            ),
+            None => "Reply with a single valid JSON object and nothing else, no explanation \
+                     and no code fence."
+                .to_owned(),
+        }
+    }
+
+    /// JSON text of the reply, or the reason why the reply is invalid
+    pub fn extract(&self, text: &str) -> Result<String, String> {
+        let json = extract_json(text).ok_or_else(|| "the reply is not valid JSON".to_owned())?;
+        let value = serde_json::from_str::<Value>(json).map_err(|err| err.to_string())?;
+
+        match self.validator {
+            Some(ref validator) => {
+                let errors = validator
+                    .iter_errors(&value)
+                    .map(|err| match err.instance_path.as_str() {
+                        "" => err.to_string(),
+                        path => format!("{path}: {err}"),
+                    })
+                    .collect::<Vec<_>>();
+                if !errors.is_empty() {
+                    return Err(errors.join("; "));
+                }
+            }
+            None if !value.is_object() => {
+                return Err("the reply is not a JSON object".to_owned());
+            }
+            None => {}
+        }
+
+        Ok(json.to_owned())
+    }
+
+    /// User turn asking the model to fix an invalid reply
+    pub fn reask(&self, reason: &str) -> String {
+        format!(
+            "Your previous reply is invalid: {reason}.\n\
+             Reply again with only the corrected JSON."
+        )
+    }
+}
+
+/// JSON value of the reply, stripped of a code fence or surrounding text
+fn extract_json(text: &str) -> Option<&str> {
+    let text = tools::strip_fence(text);
+    if serde_json::from_str::<Value>(text).is_ok() {
+        return Some(text);
+    }
+
+    // Outermost object or array around the explanations of the model
+    let start = text.find(['{', '['])?;
+    let end = match text.as_bytes()[start] {
+        b'{' => text.rfind('}')?,
+        _ => text.rfind(']')?,
+    };
+    let json = text.get(start..=end)?;
+    serde_json::from_str::<Value>(json).ok().map(|_| json)
+}
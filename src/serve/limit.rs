@@ -0,0 +1,260 @@
+use super::auth::ApiKey;
+use crate::{
+    config::{LimitConfig, RateLimitConfig},
+    error::Error,
+};
+use axum::http::{HeaderMap, HeaderValue};
+use moka::future::Cache;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const DAY: u64 = 86400;
+
+/// Rate limit state per API key and per client address, kept across reloads
+pub struct RateLimiter {
+    keys: Cache<String, Arc<Bucket>>,
+    ips: Cache<IpAddr, Arc<Bucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        // A bucket idle for a day holds no state worth keeping
+        Self {
+            keys: Cache::builder()
+                .time_to_idle(Duration::from_secs(DAY))
+                .build(),
+            ips: Cache::builder()
+                .time_to_idle(Duration::from_secs(DAY))
+                .build(),
+        }
+    }
+}
+
+impl RateLimiter {
+    /// Check the limits of the API key and the client address, the returned
+    /// permit holds the concurrency slots until it is dropped
+    pub async fn acquire(
+        &self,
+        config: &RateLimitConfig,
+        key: Option<&ApiKey>,
+        ip: IpAddr,
+    ) -> crate::Result<Permit> {
+        let mut permit = Permit::default();
+        let key = match key {
+            Some(key) => self.acquire_key(config, key, &mut permit).await?,
+            None => None,
+        };
+
+        if !config.ip.is_unlimited() {
+            let bucket = self.ips.get_with(ip, async { Default::default() }).await;
+            if let Err(err) = bucket.acquire(&config.ip, &format!("address {ip}"), &mut permit) {
+                // The request is not sent, give back what the API key spent on it
+                if let Some((bucket, limit)) = key {
+                    bucket.refund(limit);
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(permit)
+    }
+
+    /// Check the limits of the API key alone, for requests that do not come
+    /// from a client address such as batch requests
+    pub async fn acquire_for_key(
+        &self,
+        config: &RateLimitConfig,
+        key: &ApiKey,
+    ) -> crate::Result<Permit> {
+        let mut permit = Permit::default();
+        self.acquire_key(config, key, &mut permit).await?;
+        Ok(permit)
+    }
+
+    /// Check the limits of the API key, returns its bucket and limits when
+    /// the key is limited
+    async fn acquire_key<'a>(
+        &self,
+        config: &'a RateLimitConfig,
+        key: &'a ApiKey,
+        permit: &mut Permit,
+    ) -> crate::Result<Option<(Arc<Bucket>, &'a LimitConfig)>> {
+        let limit = key.rate_limit().unwrap_or(&config.key);
+        if limit.is_unlimited() {
+            return Ok(None);
+        }
+
+        let bucket = self
+            .keys
+            .get_with(key.name().to_owned(), async { Default::default() })
+            .await;
+        bucket.acquire(limit, &format!("API key '{}'", key.name()), permit)?;
+        Ok(Some((bucket, limit)))
+    }
+}
+
+/// Limits state of one API key or client address
+#[derive(Default)]
+struct Bucket {
+    state: Mutex<BucketState>,
+    concurrent: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct BucketState {
+    /// Token bucket, refilled continuously up to requests per minute
+    tokens: f64,
+    refilled: Option<Instant>,
+
+    /// Requests of the current UTC day
+    day: u64,
+    daily: u64,
+}
+
+impl Bucket {
+    fn acquire(&self, limit: &LimitConfig, scope: &str, permit: &mut Permit) -> crate::Result<()> {
+        if let Some(max) = limit.concurrent {
+            if self.concurrent.fetch_add(1, Ordering::AcqRel) >= max {
+                self.concurrent.fetch_sub(1, Ordering::AcqRel);
+                return Err(Error::RateLimited {
+                    message: format!(
+                        "Rate limit reached for {scope} on concurrent requests: Limit {max}. Please try again when a running request completes."
+                    ),
+                    limit: max as u64,
+                    reset: Duration::from_secs(1),
+                });
+            }
+            permit.slots.push(self.concurrent.clone());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let (day, until_tomorrow) = utc_day();
+
+        if let Some(max) = limit.daily_requests {
+            if state.day != day {
+                state.day = day;
+                state.daily = 0;
+            }
+
+            if state.daily >= max {
+                return Err(Error::RateLimited {
+                    message: format!(
+                        "Rate limit reached for {scope} on requests per day: Limit {max}, Used {}. Please try again in {}s.",
+                        state.daily,
+                        until_tomorrow.as_secs()
+                    ),
+                    limit: max,
+                    reset: until_tomorrow,
+                });
+            }
+        }
+
+        if let Some(rpm) = limit.requests_per_minute {
+            let capacity = rpm as f64;
+            let rate = capacity / 60.0;
+            let now = Instant::now();
+            state.tokens = match state.refilled {
+                Some(refilled) => {
+                    (state.tokens + now.duration_since(refilled).as_secs_f64() * rate).min(capacity)
+                }
+                None => capacity,
+            };
+            state.refilled = Some(now);
+
+            if state.tokens < 1.0 {
+                let reset = Duration::from_secs_f64((1.0 - state.tokens) / rate);
+                return Err(Error::RateLimited {
+                    message: format!(
+                        "Rate limit reached for {scope} on requests per minute: Limit {rpm}. Please try again in {}ms.",
+                        reset.as_millis()
+                    ),
+                    limit: rpm as u64,
+                    reset,
+                });
+            }
+
+            state.tokens -= 1.0;
+            permit.update(
+                rpm as u64,
+                state.tokens as u64,
+                Duration::from_secs_f64((capacity - state.tokens) / rate),
+            );
+        }
+
+        if limit.daily_requests.is_some() {
+            state.daily += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Give back the request of a successful [`Bucket::acquire`], the
+    /// concurrency slot is released with the permit
+    fn refund(&self, limit: &LimitConfig) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(rpm) = limit.requests_per_minute {
+            state.tokens = (state.tokens + 1.0).min(rpm as f64);
+        }
+        if limit.daily_requests.is_some() && state.day == utc_day().0 {
+            state.daily = state.daily.saturating_sub(1);
+        }
+    }
+}
+
+/// Granted request, reports the tightest requests per minute limit
+#[derive(Default)]
+pub struct Permit {
+    slots: Vec<Arc<AtomicUsize>>,
+    requests: Option<(u64, u64, Duration)>,
+}
+
+impl Permit {
+    fn update(&mut self, limit: u64, remaining: u64, reset: Duration) {
+        if self
+            .requests
+            .map_or(true, |(_, current, _)| remaining < current)
+        {
+            self.requests = Some((limit, remaining, reset));
+        }
+    }
+
+    /// `x-ratelimit-*` response headers
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some((limit, remaining, reset)) = self.requests {
+            headers.insert("x-ratelimit-limit-requests", limit.into());
+            headers.insert("x-ratelimit-remaining-requests", remaining.into());
+            headers.insert("x-ratelimit-reset-requests", reset_value(reset));
+        }
+        headers
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        for slot in self.slots.iter() {
+            slot.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Format a reset duration the way OpenAI does, e.g. `1.5s`
+pub fn reset_value(reset: Duration) -> HeaderValue {
+    HeaderValue::from_str(&format!("{}s", reset.as_millis() as f64 / 1000.0))
+        .expect("valid header value")
+}
+
+/// Current UTC day and the time left until the next one
+fn utc_day() -> (u64, Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let day = now.as_secs() / DAY;
+    (day, Duration::from_secs((day + 1) * DAY) - now)
+}
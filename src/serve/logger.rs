@@ -0,0 +1,201 @@
+use crate::config::{LogConfig, LogFileConfig, LogFormat, LogRotation};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::Level;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{fmt, layer::SubscriberExt, EnvFilter, Layer, Registry};
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Initialize the logger with a filter that ignores WARN level logs for netlink_proto,
+/// the returned guard flushes the file sink when dropped
+pub fn init(debug: bool, config: &LogConfig) -> crate::Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::from_default_env()
+        .add_directive(if debug { Level::DEBUG } else { Level::INFO }.into())
+        .add_directive("netlink_proto=error".parse()?);
+
+    let mut layers: Vec<BoxedLayer> = Vec::new();
+    if config.stdout {
+        layers.push(layer(config.format, io::stdout, true));
+    }
+
+    let guard = match config.file {
+        Some(ref file) => {
+            let (writer, guard) = tracing_appender::non_blocking(RollingFile::new(file)?);
+            layers.push(layer(config.format, writer, false));
+            Some(guard)
+        }
+        None => None,
+    };
+
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(layers).with(filter),
+    )?;
+
+    Ok(guard)
+}
+
+fn layer<W>(format: LogFormat, writer: W, ansi: bool) -> BoxedLayer
+where
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_ansi(ansi).with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(writer)
+            .boxed(),
+    }
+}
+
+/// Log file rotated by time and size, rotated files are suffixed with their
+/// rotation time, plus a sequence number for files rotated within the same
+/// second, and the oldest ones are removed past `max_files`
+struct RollingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_size: Option<u64>,
+    max_files: usize,
+
+    file: File,
+    size: u64,
+    period: u64,
+}
+
+impl RollingFile {
+    fn new(config: &LogFileConfig) -> io::Result<Self> {
+        if let Some(dir) = config
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = open(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: config.path.clone(),
+            rotation: config.rotation,
+            max_size: config.max_size.map(|mb| mb * 1024 * 1024),
+            max_files: config.max_files,
+            file,
+            size,
+            period: period(config.rotation),
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let rotated = self.rotated_path(secs);
+        fs::rename(&self.path, &rotated)?;
+
+        self.file = open(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation);
+        self.prune();
+        Ok(())
+    }
+
+    /// Path of a file rotated at `secs`, files rotated within the same second
+    /// get an increasing sequence number so that none is overwritten
+    fn rotated_path(&self, secs: u64) -> PathBuf {
+        let mut seq = 0;
+        loop {
+            let mut rotated = self.path.as_os_str().to_owned();
+            match seq {
+                0 => rotated.push(format!(".{secs}")),
+                seq => rotated.push(format!(".{secs}.{seq}")),
+            }
+            let rotated = PathBuf::from(rotated);
+            if !rotated.exists() {
+                return rotated;
+            }
+            seq += 1;
+        }
+    }
+
+    /// Remove the oldest rotated files
+    fn prune(&self) {
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return;
+        };
+        let dir = if dir.as_os_str().is_empty() {
+            PathBuf::from(".")
+        } else {
+            dir.to_path_buf()
+        };
+        let prefix = format!("{}.", name.to_string_lossy());
+
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return;
+        };
+        let mut rotated = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let suffix = name.strip_prefix(&prefix)?;
+                let (secs, seq) = match suffix.split_once('.') {
+                    Some((secs, seq)) => (secs.parse::<u64>().ok()?, seq.parse::<u64>().ok()?),
+                    None => (suffix.parse::<u64>().ok()?, 0),
+                };
+                Some(((secs, seq), entry.path()))
+            })
+            .collect::<Vec<_>>();
+
+        if rotated.len() > self.max_files {
+            rotated.sort_unstable();
+            let excess = rotated.len() - self.max_files;
+            for (_, path) in rotated.into_iter().take(excess) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let oversized = self.max_size.map_or(false, |max_size| {
+            self.size > 0 && self.size + buf.len() as u64 > max_size
+        });
+        if oversized || period(self.rotation) != self.period {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Current rotation period, in units of the rotation interval since the epoch
+fn period(rotation: LogRotation) -> u64 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match rotation {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => secs / 3600,
+        LogRotation::Daily => secs / 86400,
+    }
+}
@@ -0,0 +1,190 @@
+use super::client::PoolClientStats;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_util::MetricKindMask;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Histogram buckets (seconds), chat requests range from milliseconds to minutes
+const BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+
+/// Prometheus recorder and the gauges sampled on scrape
+pub struct Metrics {
+    handle: PrometheusHandle,
+    in_flight: AtomicUsize,
+    concurrent: usize,
+}
+
+impl Metrics {
+    /// Install the global recorder, must only be called once
+    pub fn install(concurrent: usize) -> crate::Result<Arc<Self>> {
+        // Per client gauges of clients dropped by a reload or a CIDR cache
+        // eviction disappear once they stop being sampled
+        let handle = PrometheusBuilder::new()
+            .set_buckets(BUCKETS)?
+            .idle_timeout(MetricKindMask::GAUGE, Some(Duration::from_secs(300)))
+            .install_recorder()?;
+
+        let upkeep = handle.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                upkeep.run_upkeep();
+            }
+        });
+
+        Ok(Arc::new(Self {
+            handle,
+            in_flight: AtomicUsize::new(0),
+            concurrent,
+        }))
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self, clients: Vec<PoolClientStats>) -> String {
+        metrics::gauge!("duckai_http_requests_in_flight")
+            .set(self.in_flight.load(Ordering::Relaxed) as f64);
+        metrics::gauge!("duckai_http_concurrency_limit").set(self.concurrent as f64);
+
+        for client in clients {
+            let name = client.name;
+            metrics::gauge!("duckai_pool_client_in_flight", "client" => name.clone())
+                .set(client.in_flight as f64);
+            // Counters do not expire, only export those of failing clients so
+            // that short-lived CIDR clients do not pile up
+            if client.failures_total > 0 {
+                metrics::counter!("duckai_pool_client_failures_total", "client" => name.clone())
+                    .absolute(client.failures_total);
+            }
+            metrics::gauge!("duckai_pool_client_consecutive_failures", "client" => name.clone())
+                .set(client.failures as f64);
+            metrics::gauge!("duckai_pool_client_healthy", "client" => name.clone())
+                .set(if client.healthy { 1.0 } else { 0.0 });
+            metrics::gauge!("duckai_pool_client_tokens_available", "client" => name)
+                .set(client.tokens.available as f64);
+        }
+
+        self.handle.render()
+    }
+}
+
+/// Labels filled in by the handlers, read back once the response is produced
+#[derive(Clone, Default)]
+pub struct RequestLabels {
+    model: Arc<Mutex<Option<String>>>,
+}
+
+impl RequestLabels {
+    pub fn set_model(&self, model: &str) {
+        *self.model.lock().unwrap() = Some(model.to_owned());
+    }
+
+    fn model(&self) -> String {
+        self.model.lock().unwrap().clone().unwrap_or_default()
+    }
+}
+
+/// Count requests and their latency by route, model and status, streamed
+/// responses are measured until their headers are sent
+pub async fn track(State(metrics): State<Arc<Metrics>>, mut req: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "fallback".to_owned());
+    let method = req.method().to_string();
+    let labels = RequestLabels::default();
+    req.extensions_mut().insert(labels.clone());
+
+    let resp = {
+        let _in_flight = InFlight::new(&metrics.in_flight);
+        next.run(req).await
+    };
+
+    let labels = [
+        ("route", route),
+        ("method", method),
+        ("status", resp.status().as_u16().to_string()),
+        ("model", labels.model()),
+    ];
+    metrics::counter!("duckai_http_requests_total", &labels).increment(1);
+    metrics::histogram!("duckai_http_request_duration_seconds", &labels)
+        .record(started.elapsed().as_secs_f64());
+
+    resp
+}
+
+/// Decrements the in-flight gauge even if the request is cancelled
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl<'a> InFlight<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Upstream chat request that did not succeed, labelled by upstream status or
+/// by failure kind
+pub fn upstream_error(status: &str) {
+    metrics::counter!("duckai_upstream_errors_total", "status" => status.to_owned()).increment(1);
+}
+
+/// `x-vqd-4` token fetch from the status endpoint
+pub fn token_fetch(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    metrics::counter!("duckai_vqd_token_fetches_total", "result" => result).increment(1);
+}
+
+/// Streamed chat response timings, the stream duration is recorded when the
+/// stream ends or the client goes away
+pub struct StreamTimer {
+    model: String,
+    started: Instant,
+    first_token: bool,
+}
+
+impl StreamTimer {
+    pub fn new(model: String, started: Instant) -> Self {
+        Self {
+            model,
+            started,
+            first_token: false,
+        }
+    }
+
+    /// Record the time to first token, only the first call counts
+    pub fn first_token(&mut self) {
+        if !self.first_token {
+            self.first_token = true;
+            metrics::histogram!("duckai_time_to_first_token_seconds", "model" => self.model.clone())
+                .record(self.started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+impl Drop for StreamTimer {
+    fn drop(&mut self) {
+        metrics::histogram!("duckai_stream_duration_seconds", "model" => self.model.clone())
+            .record(self.started.elapsed().as_secs_f64());
+    }
+}
@@ -1,14 +1,36 @@
+mod anthropic;
+mod auth;
+mod batch;
+mod catalog;
 mod client;
+mod format;
+mod limit;
+mod logger;
+mod metrics;
 mod model;
+mod ollama;
+mod output;
+mod responses;
 mod route;
 mod signal;
+pub(crate) mod tokenizer;
+mod tools;
 
+use self::metrics::Metrics;
 use crate::Result;
-use crate::{config::Config, error::Error};
+use crate::{
+    config::{
+        AffinityConfig, AffinitySource, Config, MessageMode, RateLimitConfig, ResponseFormatConfig,
+        RetryConfig,
+    },
+    error::Error,
+};
+use auth::{ApiKey, ApiKeys};
 use axum::Json;
 use axum::{
+    body::Body,
     extract::DefaultBodyLimit,
-    http::StatusCode,
+    http::{header, HeaderMap, Request, StatusCode},
     response::IntoResponse,
     routing::{get, post},
     Router,
@@ -17,28 +39,102 @@ use axum_extra::headers::authorization::Bearer;
 use axum_extra::headers::Authorization;
 use axum_extra::TypedHeader;
 use axum_server::{tls_boringssl::BoringSSLConfig, Handle};
+use batch::BatchStore;
+use catalog::ModelCatalog;
 use client::ClientLoadBalancer;
 use hyper_util::rt::TokioTimer;
+use limit::RateLimiter;
+use responses::ResponseStore;
 use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::{path::PathBuf, time::Duration};
 use tower::limit::ConcurrencyLimitLayer;
 use tower_http::{
     cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer},
-    trace::{DefaultMakeSpan, DefaultOnFailure, DefaultOnResponse, TraceLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::{DefaultOnFailure, DefaultOnResponse, TraceLayer},
 };
 use tracing::Level;
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
 use typed_builder::TypedBuilder;
 
-#[derive(Clone, TypedBuilder)]
+/// Shared server state, the runtime snapshot is swapped on reload
+#[derive(Clone)]
 pub struct AppState {
+    runtime: Arc<RwLock<Arc<Runtime>>>,
+    limiter: Arc<RateLimiter>,
+    responses: Arc<ResponseStore>,
+    batches: Arc<BatchStore>,
+    metrics: Arc<Metrics>,
+}
+
+impl AppState {
+    pub fn new(
+        runtime: Runtime,
+        responses: ResponseStore,
+        batches: BatchStore,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            runtime: Arc::new(RwLock::new(Arc::new(runtime))),
+            limiter: Default::default(),
+            responses: Arc::new(responses),
+            batches: Arc::new(batches),
+            metrics,
+        }
+    }
+
+    #[inline]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Rate limit state, not reset by reloads
+    #[inline]
+    pub fn limiter(&self) -> Arc<RateLimiter> {
+        self.limiter.clone()
+    }
+
+    /// Stored `/v1/responses` conversations, not reset by reloads
+    #[inline]
+    pub fn responses(&self) -> Arc<ResponseStore> {
+        self.responses.clone()
+    }
+
+    /// Batch API files and jobs, not reset by reloads
+    #[inline]
+    pub fn batches(&self) -> Arc<BatchStore> {
+        self.batches.clone()
+    }
+
+    /// Current runtime snapshot, in-flight requests keep their snapshot alive
+    /// across reloads
+    #[inline]
+    pub fn load(&self) -> Arc<Runtime> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    /// Atomically replace the runtime snapshot
+    pub fn store(&self, runtime: Runtime) {
+        *self.runtime.write().unwrap() = Arc::new(runtime);
+    }
+}
+
+/// Reloadable server state
+#[derive(TypedBuilder)]
+pub struct Runtime {
     client: ClientLoadBalancer,
-    api_key: Arc<Option<String>>,
+    api_keys: ApiKeys,
+    message_mode: MessageMode,
+    models: ModelCatalog,
+    retry: RetryConfig,
+    response_format: ResponseFormatConfig,
+    affinity: AffinityConfig,
+    rate_limit: RateLimitConfig,
 }
 
-impl Deref for AppState {
+impl Deref for Runtime {
     type Target = ClientLoadBalancer;
 
     fn deref(&self) -> &Self::Target {
@@ -46,42 +142,194 @@ impl Deref for AppState {
     }
 }
 
-impl AppState {
+impl Runtime {
+    pub async fn new(config: &Config) -> Result<Self> {
+        Ok(Runtime::builder()
+            .client(ClientLoadBalancer::new(config.clone()).await?)
+            .api_keys(ApiKeys::new(config)?)
+            .message_mode(config.message_mode)
+            .models(ModelCatalog::new(
+                config.models.clone(),
+                config.reject_unknown_model,
+            ))
+            .retry(config.retry.clone())
+            .response_format(config.response_format.clone())
+            .affinity(config.affinity.clone())
+            .rate_limit(config.rate_limit.clone())
+            .build())
+    }
+
+    #[inline]
+    pub fn message_mode(&self) -> MessageMode {
+        self.message_mode
+    }
+
     #[inline]
-    pub fn valid_key(
+    pub fn models(&self) -> &ModelCatalog {
+        &self.models
+    }
+
+    #[inline]
+    pub fn retry(&self) -> &RetryConfig {
+        &self.retry
+    }
+
+    #[inline]
+    pub fn response_format(&self) -> &ResponseFormatConfig {
+        &self.response_format
+    }
+
+    #[inline]
+    pub fn rate_limit(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+
+    /// Client address, taken from `rate_limit.ip_header` when configured
+    pub fn client_ip(&self, headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+        self.rate_limit
+            .ip_header
+            .as_ref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or_else(|| addr.ip())
+    }
+
+    /// Sticky session key of the request, if affinity is enabled
+    pub fn affinity_key(
+        &self,
+        headers: &HeaderMap,
+        api_key: Option<&str>,
+        user: Option<&str>,
+    ) -> Option<String> {
+        let key = match self.affinity.source? {
+            AffinitySource::Header => headers
+                .get(&self.affinity.header)
+                .and_then(|value| value.to_str().ok()),
+            AffinitySource::User => user,
+            AffinitySource::ApiKey => api_key,
+        };
+        key.filter(|key| !key.is_empty()).map(ToOwned::to_owned)
+    }
+
+    /// Authenticate the request and record the key name on the request span,
+    /// returns `None` when no key is configured
+    pub fn authenticate(
         &self,
-        bearer: Option<TypedHeader<Authorization<Bearer>>>,
-    ) -> crate::Result<()> {
-        let api_key = bearer.as_deref().map(|b| b.token());
-        self.api_key.as_deref().map_or(Ok(()), |key| {
-            if api_key.map_or(false, |api_key| api_key == key) {
-                Ok(())
-            } else {
-                Err(crate::Error::InvalidApiKey)
+        bearer: Option<&TypedHeader<Authorization<Bearer>>>,
+    ) -> crate::Result<Option<&ApiKey>> {
+        self.authenticate_token(bearer.map(|b| b.token()))
+    }
+
+    /// API key by name, for work queued by a key such as batches
+    pub fn api_key(&self, name: &str) -> crate::Result<&ApiKey> {
+        self.api_keys.find(name)
+    }
+
+    /// Authenticate a raw key, for APIs that do not use bearer auth
+    pub fn authenticate_token(&self, token: Option<&str>) -> crate::Result<Option<&ApiKey>> {
+        let key = self.api_keys.authenticate(token)?;
+        if let Some(key) = key {
+            tracing::Span::current().record("api_key", key.name());
+        }
+        Ok(key)
+    }
+}
+
+/// Configuration hot reload
+pub struct Reloader {
+    path: PathBuf,
+    state: AppState,
+    config: Config,
+}
+
+impl Reloader {
+    /// Re-read the configuration file and swap the runtime state, the current
+    /// state is kept if the new configuration is invalid
+    pub async fn reload(&mut self) {
+        match self.try_reload().await {
+            Ok(config) => {
+                warn_restart_required(&self.config, &config);
+                self.config = config;
+                tracing::info!("Configuration reloaded from {}", self.path.display());
             }
-        })
+            Err(err) => {
+                tracing::error!("Failed to reload configuration, keeping the current one: {err}")
+            }
+        }
+    }
+
+    async fn try_reload(&self) -> Result<Config> {
+        if !self.path.is_file() {
+            return Err(Error::InvalidConfig(format!(
+                "{} is not a file",
+                self.path.display()
+            )));
+        }
+
+        let config = init_config(self.path.clone()).await?;
+        config.validate()?;
+        self.state.store(Runtime::new(&config).await?);
+        Ok(config)
+    }
+}
+
+/// Warn about settings that only take effect after a restart
+fn warn_restart_required(old: &Config, new: &Config) {
+    let changed = [
+        ("debug", old.debug != new.debug),
+        ("log", old.log != new.log),
+        ("bind", old.bind != new.bind),
+        ("concurrent", old.concurrent != new.concurrent),
+        ("tls_cert", old.tls_cert != new.tls_cert),
+        ("tls_key", old.tls_key != new.tls_key),
+        ("responses", old.responses != new.responses),
+        ("batch", old.batch != new.batch),
+    ];
+
+    for (name, _) in changed.iter().filter(|(_, changed)| *changed) {
+        tracing::warn!("'{name}' changed, restart the server to apply it");
     }
 }
 
 #[tokio::main]
 pub async fn run(path: PathBuf) -> Result<()> {
     // init config
-    let config = init_config(path).await?;
+    let config = init_config(path.clone()).await?;
+    config.validate()?;
 
-    // init logger
-    init_logger(config.debug)?;
+    // init logger, the guard flushes the log file on exit
+    let _log_guard = logger::init(config.debug, &config.log)?;
 
     // init boot message
     boot_message(&config);
 
     // init global layer provider
     let global_layer = tower::ServiceBuilder::new()
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .layer(
             TraceLayer::new_for_http()
-                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .make_span_with(|request: &Request<Body>| {
+                    let request_id = request
+                        .headers()
+                        .get("x-request-id")
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default();
+                    tracing::info_span!(
+                        "request",
+                        request_id,
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        version = ?request.version(),
+                        api_key = tracing::field::Empty,
+                        client = tracing::field::Empty,
+                    )
+                })
                 .on_response(DefaultOnResponse::new().level(Level::INFO))
                 .on_failure(DefaultOnFailure::new().level(Level::WARN)),
         )
+        .layer(PropagateRequestIdLayer::x_request_id())
         .layer(
             CorsLayer::new()
                 .allow_credentials(true)
@@ -92,24 +340,62 @@ pub async fn run(path: PathBuf) -> Result<()> {
         .layer(DefaultBodyLimit::max(209715200))
         .layer(ConcurrencyLimitLayer::new(config.concurrent));
 
-    let app_state = AppState::builder()
-        .client(ClientLoadBalancer::new(config.clone()).await)
-        .api_key(Arc::new(config.api_key))
-        .build();
+    // init metrics recorder
+    let metrics = Metrics::install(config.concurrent)?;
+
+    let app_state = AppState::new(
+        Runtime::new(&config).await?,
+        ResponseStore::new(&config.responses),
+        BatchStore::open(&config.batch).await?,
+        metrics.clone(),
+    );
+
+    // resume the batches interrupted by the last shutdown
+    batch::resume(&app_state);
 
     let router = Router::new()
         .route("/ping", get(route::ping))
         .route("/v1/models", get(route::models))
         .route("/v1/chat/completions", post(route::chat_completions))
+        .route("/v1/completions", post(route::completions))
+        .route("/v1/responses", post(route::responses))
+        .route("/v1/files", post(route::upload_file).get(route::list_files))
+        .route(
+            "/v1/files/:file_id",
+            get(route::retrieve_file).delete(route::delete_file),
+        )
+        .route("/v1/files/:file_id/content", get(route::file_content))
+        .route(
+            "/v1/batches",
+            post(route::create_batch).get(route::list_batches),
+        )
+        .route("/v1/batches/:batch_id", get(route::retrieve_batch))
+        .route("/v1/batches/:batch_id/cancel", post(route::cancel_batch))
+        .route("/v1/messages", post(route::messages))
+        .route("/api/chat", post(route::ollama_chat))
+        .route("/api/generate", post(route::ollama_generate))
+        .route("/api/tags", get(route::ollama_tags))
+        .route("/api/show", post(route::ollama_show))
+        .route("/stats/tokens", get(route::token_stats))
+        .route("/metrics", get(route::metrics))
         .fallback(route::manual_hello)
-        .with_state(app_state)
+        .with_state(app_state.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            metrics,
+            metrics::track,
+        ))
         .layer(global_layer);
 
     // Signal the server to shutdown using Handle.
     let handle = Handle::new();
 
-    // Spawn a task to gracefully shutdown server.
-    tokio::spawn(signal::graceful_shutdown(handle.clone()));
+    // Spawn a task to gracefully shutdown server and reload configuration.
+    let reloader = Reloader {
+        path,
+        state: app_state,
+        config: config.clone(),
+    };
+    tokio::spawn(signal::graceful_shutdown(handle.clone(), reloader));
 
     // http server tcp keepalive
     let tcp_keepalive = config.tcp_keepalive.map(Duration::from_secs);
@@ -133,7 +419,7 @@ pub async fn run(path: PathBuf) -> Result<()> {
 
             server
                 .handle(handle)
-                .serve(router.into_make_service())
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
                 .await
         }
         _ => {
@@ -150,7 +436,7 @@ pub async fn run(path: PathBuf) -> Result<()> {
 
             server
                 .handle(handle)
-                .serve(router.into_make_service())
+                .serve(router.into_make_service_with_connect_info::<SocketAddr>())
                 .await
         }
     }
@@ -169,6 +455,45 @@ fn boot_message(config: &Config) {
         tracing::info!("Keepalive {} seconds", tcp_keepalive);
     }
     tracing::info!("Concurrent limit: {}", config.concurrent);
+    tracing::info!("Balance strategy: {:?}", config.balance);
+    if let Some(source) = config.affinity.source {
+        tracing::info!(
+            "Sticky sessions: {:?}, ttl {} seconds",
+            source,
+            config.affinity.ttl
+        );
+    }
+    if config
+        .proxies
+        .iter()
+        .any(|p| matches!(p, crate::proxy::Proxies::CIDR(_)))
+    {
+        tracing::info!(
+            "CIDR rotation: {:?}, cache size {}",
+            config.cidr.rotation,
+            config.cidr.cache_size
+        );
+    }
+    if !config.api_keys.is_empty() {
+        tracing::info!("API keys: {}", config.api_keys.len());
+    }
+    if !config.rate_limit.key.is_unlimited() || !config.rate_limit.ip.is_unlimited() {
+        tracing::info!(
+            "Rate limit: key {:?}, ip {:?}",
+            config.rate_limit.key,
+            config.rate_limit.ip
+        );
+    }
+    tracing::info!("Message mode: {:?}", config.message_mode);
+    tracing::info!(
+        "Retry: {} attempts, statuses {:?}",
+        config.retry.max_attempts,
+        config.retry.statuses
+    );
+    config
+        .models
+        .iter()
+        .for_each(|m| tracing::info!("Model: {} -> {}", m.id, m.upstream));
     config
         .proxies
         .iter()
@@ -176,19 +501,6 @@ fn boot_message(config: &Config) {
     tracing::info!("Bind address: {}", config.bind);
 }
 
-/// Initialize the logger with a filter that ignores WARN level logs for netlink_proto
-fn init_logger(debug: bool) -> Result<()> {
-    let filter = EnvFilter::from_default_env()
-        .add_directive(if debug { Level::DEBUG } else { Level::INFO }.into())
-        .add_directive("netlink_proto=error".parse()?);
-
-    tracing::subscriber::set_global_default(
-        FmtSubscriber::builder().with_env_filter(filter).finish(),
-    )?;
-
-    Ok(())
-}
-
 /// Init configuration
 async fn init_config(path: PathBuf) -> Result<Config> {
     if !path.is_file() {
@@ -200,55 +512,235 @@ async fn init_config(path: PathBuf) -> Result<Config> {
     serde_yaml::from_slice::<Config>(&data).map_err(Into::into)
 }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> axum::response::Response {
-        #[derive(Serialize)]
-        struct RootError {
-            error: ResponseError,
-        }
+/// OpenAI error response body
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: ErrorObject,
+}
 
-        #[derive(Serialize, TypedBuilder)]
-        struct ResponseError {
-            message: String,
-            #[serde(rename = "type")]
-            type_field: &'static str,
-            #[builder(default)]
-            param: Option<String>,
-            #[builder(default)]
-            code: Option<String>,
-        }
+/// OpenAI error object
+#[derive(Serialize, TypedBuilder)]
+pub struct ErrorObject {
+    message: String,
+    #[serde(rename = "type")]
+    type_field: &'static str,
+    #[builder(default)]
+    param: Option<String>,
+    #[builder(default, setter(strip_option))]
+    code: Option<&'static str>,
+}
 
+impl ErrorObject {
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    #[inline]
+    pub fn code(&self) -> Option<&'static str> {
+        self.code
+    }
+}
+
+impl Error {
+    /// HTTP status and OpenAI error object of the error
+    pub fn to_openai(&self) -> (StatusCode, ErrorObject) {
         match self {
             Error::JsonExtractorRejection(json_rejection) => (
                 StatusCode::BAD_REQUEST,
-                Json(RootError {
-                    error: ResponseError::builder()
-                        .message(json_rejection.body_text())
-                        .type_field("invalid_request_error")
-                        .build(),
-                }),
-            )
-                .into_response(),
+                ErrorObject::builder()
+                    .message(json_rejection.body_text())
+                    .type_field("invalid_request_error")
+                    .build(),
+            ),
+            Error::ModelNotFound(_) => (
+                StatusCode::NOT_FOUND,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .param(Some("model".to_owned()))
+                    .code("model_not_found")
+                    .build(),
+            ),
+            Error::NotFound(..) => (
+                StatusCode::NOT_FOUND,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .build(),
+            ),
+            Error::InvalidParameter(param, _) => (
+                StatusCode::BAD_REQUEST,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .param(Some((*param).to_owned()))
+                    .code("invalid_value")
+                    .build(),
+            ),
+            Error::RateLimited { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("requests")
+                    .code("rate_limit_exceeded")
+                    .build(),
+            ),
             Error::InvalidApiKey => (
                 StatusCode::UNAUTHORIZED,
-                Json(RootError {
-                    error: ResponseError::builder()
-                        .message(self.to_string())
-                        .type_field("invalid_request_error")
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("invalid_request_error")
+                    .code("invalid_api_key")
+                    .build(),
+            ),
+            Error::UpstreamStatus(status, body) => upstream_status(*status, Some(body)),
+            Error::RequestError(err) => match err.status() {
+                Some(status) => upstream_status(status, None),
+                None if err.is_timeout() => (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    ErrorObject::builder()
+                        .message(format!("Upstream request timed out: {err}"))
+                        .type_field("server_error")
+                        .code("upstream_timeout")
                         .build(),
-                }),
-            )
-                .into_response(),
-            _ => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(RootError {
-                    error: ResponseError::builder()
-                        .message(self.to_string())
+                ),
+                None if err.is_connect() => (
+                    StatusCode::BAD_GATEWAY,
+                    ErrorObject::builder()
+                        .message(format!("Failed to connect to the upstream: {err}"))
                         .type_field("server_error")
+                        .code("upstream_connect_error")
                         .build(),
-                }),
-            )
-                .into_response(),
+                ),
+                None => (
+                    StatusCode::BAD_GATEWAY,
+                    ErrorObject::builder()
+                        .message(format!("Upstream request failed: {err}"))
+                        .type_field("server_error")
+                        .code("upstream_error")
+                        .build(),
+                ),
+            },
+            Error::UpstreamStream(_) => (
+                StatusCode::BAD_GATEWAY,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .code("upstream_stream_error")
+                    .build(),
+            ),
+            Error::InvalidResponseFormat(_) => (
+                StatusCode::BAD_GATEWAY,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .param(Some("response_format".to_owned()))
+                    .code("invalid_response_format")
+                    .build(),
+            ),
+            Error::MissingHeader => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                ErrorObject::builder()
+                    .message(format!(
+                        "The upstream did not issue a conversation token, please retry later: {self}"
+                    ))
+                    .type_field("server_error")
+                    .code("upstream_unavailable")
+                    .build(),
+            ),
+            _ => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorObject::builder()
+                    .message(self.to_string())
+                    .type_field("server_error")
+                    .code("internal_error")
+                    .build(),
+            ),
+        }
+    }
+}
+
+/// Map an upstream status: 429 stays a rate limit, other client errors are
+/// reported as invalid requests and anything else as a bad gateway
+fn upstream_status(status: rquest::StatusCode, body: Option<&str>) -> (StatusCode, ErrorObject) {
+    let message = body
+        .and_then(upstream_message)
+        .unwrap_or_else(|| format!("Upstream responded with {status}"));
+
+    match status.as_u16() {
+        429 => (
+            StatusCode::TOO_MANY_REQUESTS,
+            ErrorObject::builder()
+                .message(message)
+                .type_field("requests")
+                .code("rate_limit_exceeded")
+                .build(),
+        ),
+        400..=499 => (
+            StatusCode::BAD_REQUEST,
+            ErrorObject::builder()
+                .message(message)
+                .type_field("invalid_request_error")
+                .code("upstream_invalid_request")
+                .build(),
+        ),
+        _ => (
+            StatusCode::BAD_GATEWAY,
+            ErrorObject::builder()
+                .message(message)
+                .type_field("server_error")
+                .code("upstream_error")
+                .build(),
+        ),
+    }
+}
+
+/// Extract the message of an upstream error body, either JSON or plain text
+fn upstream_message(body: &str) -> Option<String> {
+    let body = body.trim();
+    if body.is_empty() {
+        return None;
+    }
+
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(body) else {
+        return Some(body.to_owned());
+    };
+
+    let text = |value: &serde_json::Value| value.as_str().map(ToOwned::to_owned);
+    text(&value["message"])
+        .or_else(|| text(&value["error"]["message"]))
+        .or_else(|| text(&value["error"]))
+        .or_else(|| text(&value["type"]))
+        .or_else(|| Some(body.to_owned()))
+}
+
+impl Error {
+    /// `Retry-After` and rate limit headers of a rate limited request
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Error::RateLimited { limit, reset, .. } = *self {
+            headers.insert(
+                header::RETRY_AFTER,
+                reset
+                    .as_secs_f64()
+                    .ceil()
+                    .max(1.0)
+                    .to_string()
+                    .parse()
+                    .unwrap(),
+            );
+            headers.insert("x-ratelimit-limit-requests", limit.into());
+            headers.insert("x-ratelimit-remaining-requests", 0.into());
+            headers.insert("x-ratelimit-reset-requests", limit::reset_value(reset));
         }
+        headers
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        let (status, error) = self.to_openai();
+        (status, self.headers(), Json(ErrorBody { error })).into_response()
     }
 }
@@ -1,16 +1,28 @@
-use serde::{Deserialize, Deserializer, Serialize};
+use super::{
+    catalog::ModelCatalog,
+    format::JsonFormat,
+    tools::{self, ToolParser},
+};
+use crate::{
+    config::{MessageMode, ModelConfig},
+    error::Error,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
 use typed_builder::TypedBuilder;
 
 /// A role of a message sender, can be:
 /// - `System`, for starting system message, that sets the tone of model
 /// - `Assistant`, for messages sent by ChatGPT
 /// - `User`, for messages sent by user
-#[derive(Debug, Serialize, Deserialize)]
+/// - `Tool`, for tool call results sent back by the user
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     System,
     Assistant,
     User,
+    Tool,
 }
 
 impl Role {
@@ -19,21 +31,89 @@ impl Role {
             Role::System => "system",
             Role::Assistant => "assistant",
             Role::User => "user",
+            Role::Tool => "tool",
         }
     }
 }
 
 // ==================== Request Body ====================
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, TypedBuilder)]
 pub struct ChatRequest {
-    #[serde(deserialize_with = "deserialize_model")]
+    #[builder(setter(into))]
     model: String,
 
-    #[serde(deserialize_with = "deserialize_message")]
     messages: Vec<Message>,
 
+    #[builder(default, setter(into))]
     #[serde(skip_serializing, default)]
     stream: Option<bool>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    user: Option<String>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    stream_options: Option<StreamOptions>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    stop: Option<Stop>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    max_tokens: Option<usize>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    max_completion_tokens: Option<usize>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    tools: Option<Vec<Tool>>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    tool_choice: Option<ToolChoice>,
+
+    #[builder(default, setter(into))]
+    #[serde(skip_serializing, default)]
+    response_format: Option<ResponseFormat>,
+}
+
+/// Stop sequences, a single string or a list
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Format of the reply, JSON formats are enforced by the proxy
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchema },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonSchema {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct StreamOptions {
+    /// Send a final chunk with the token usage of the request
+    #[serde(default)]
+    include_usage: bool,
 }
 
 impl ChatRequest {
@@ -41,57 +121,487 @@ impl ChatRequest {
         self.stream
     }
 
-    pub fn model(self) -> String {
-        self.model
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Stop sequences
+    pub fn stop(&self) -> Vec<String> {
+        match self.stop {
+            Some(Stop::One(ref stop)) => vec![stop.clone()],
+            Some(Stop::Many(ref stop)) => stop.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Completion token limit, `max_completion_tokens` replaces the
+    /// deprecated `max_tokens`
+    pub fn max_tokens(&self) -> Option<usize> {
+        self.max_completion_tokens.or(self.max_tokens)
+    }
+
+    /// Check the parameters enforced by the proxy
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.max_completion_tokens == Some(0) {
+            return Err(Error::InvalidParameter(
+                "max_completion_tokens",
+                "'max_completion_tokens' must be at least 1".to_owned(),
+            ));
+        }
+
+        if self.max_tokens == Some(0) {
+            return Err(Error::InvalidParameter(
+                "max_tokens",
+                "'max_tokens' must be at least 1".to_owned(),
+            ));
+        }
+
+        let tools = self.tools.as_deref().unwrap_or_default();
+        if tools.iter().any(|tool| tool.r#type != "function") {
+            return Err(Error::InvalidParameter(
+                "tools",
+                "Only 'function' tools are supported".to_owned(),
+            ));
+        }
+
+        match self.tool_choice {
+            Some(ToolChoice::Mode(ref mode))
+                if !matches!(mode.as_str(), "none" | "auto" | "required") =>
+            {
+                return Err(Error::InvalidParameter(
+                    "tool_choice",
+                    format!("Invalid 'tool_choice': '{mode}'"),
+                ));
+            }
+            Some(ToolChoice::Function { ref function })
+                if !tools.iter().any(|tool| tool.function.name == function.name) =>
+            {
+                return Err(Error::InvalidParameter(
+                    "tool_choice",
+                    format!(
+                        "Tool '{}' in 'tool_choice' is not in 'tools'",
+                        function.name
+                    ),
+                ));
+            }
+            _ => {}
+        }
+
+        if self.stream_options.is_some() && !self.stream.unwrap_or_default() {
+            return Err(Error::InvalidParameter(
+                "stream_options",
+                "'stream_options' is only allowed when 'stream' is true".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .map_or(false, |options| options.include_usage)
+    }
+
+    /// Map the requested model to its upstream id, returns the catalog entry
+    pub fn resolve_model<'a>(
+        &mut self,
+        catalog: &'a ModelCatalog,
+    ) -> crate::Result<&'a ModelConfig> {
+        let model = catalog.resolve(&self.model)?;
+        self.model.clone_from(&model.upstream);
+        Ok(model)
+    }
+
+    /// Render tool calls and results into plain turns and describe the tools
+    /// in a system prompt, returns the parser of the reply if tools may be
+    /// called
+    pub fn apply_tools(&mut self) -> Option<ToolParser> {
+        self.messages.iter_mut().for_each(Message::render_tools);
+
+        let tools = self.tools.take().filter(|tools| !tools.is_empty())?;
+        let choice = self.tool_choice.take();
+        if matches!(choice, Some(ToolChoice::Mode(ref mode)) if mode == "none") {
+            return None;
+        }
+
+        self.messages.insert(
+            0,
+            Message::builder()
+                .role(Role::System)
+                .content(Content::Text(tools::prompt(&tools, choice.as_ref())))
+                .build(),
+        );
+        Some(ToolParser::new(&tools))
+    }
+
+    /// Describe the expected JSON reply in a system prompt, returns the
+    /// validator of the reply for JSON formats
+    pub fn apply_response_format(&mut self) -> crate::Result<Option<JsonFormat>> {
+        let Some(format) = self.response_format.take() else {
+            return Ok(None);
+        };
+        let Some(format) = JsonFormat::new(format)? else {
+            return Ok(None);
+        };
+
+        self.messages.insert(
+            0,
+            Message::builder()
+                .role(Role::System)
+                .content(Content::Text(format.prompt()))
+                .build(),
+        );
+        Ok(Some(format))
+    }
+
+    /// Replace the conversation, e.g. to send it again in a new shape
+    pub fn set_messages(&mut self, messages: Vec<Message>) {
+        self.messages = messages;
+    }
+
+    /// Rewrite the messages into the shape accepted by the upstream
+    pub fn apply_message_mode(&mut self, mode: MessageMode) {
+        let messages = std::mem::take(&mut self.messages);
+        self.messages = match mode {
+            MessageMode::Turns => into_turns(messages),
+            MessageMode::Flatten => into_flatten(messages),
+        };
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, TypedBuilder)]
+/// Send an invalid reply back to the model along with the reason it was
+/// rejected
+pub fn push_reask(messages: &mut Vec<Message>, reply: String, reask: String) {
+    messages.push(
+        Message::builder()
+            .role(Role::Assistant)
+            .content(Content::Text(reply))
+            .build(),
+    );
+    messages.push(
+        Message::builder()
+            .role(Role::User)
+            .content(Content::Text(reask))
+            .build(),
+    );
+}
+
+/// Legacy text completion request
+#[derive(Debug, Deserialize)]
+pub struct CompletionRequest {
+    model: String,
+
+    prompt: Prompt,
+
+    #[serde(default)]
+    stream: Option<bool>,
+
+    #[serde(default)]
+    stream_options: Option<StreamOptions>,
+
+    #[serde(default)]
+    user: Option<String>,
+
+    #[serde(default)]
+    stop: Option<Stop>,
+
+    #[serde(default)]
+    max_tokens: Option<usize>,
+
+    /// Send the prompt back in front of the completion
+    #[serde(default)]
+    echo: bool,
+}
+
+/// A prompt or a batch of prompts, only single prompts are supported
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Prompt {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CompletionRequest {
+    pub fn stream(&self) -> bool {
+        self.stream.unwrap_or_default()
+    }
+
+    pub fn include_usage(&self) -> bool {
+        self.stream_options
+            .as_ref()
+            .map_or(false, |options| options.include_usage)
+    }
+
+    /// Prompt echoed in front of the completion
+    pub fn echo(&self) -> Option<String> {
+        match self.prompt {
+            Prompt::One(ref prompt) if self.echo => Some(prompt.clone()),
+            Prompt::Many(ref prompts) if self.echo => prompts.first().cloned(),
+            _ => None,
+        }
+    }
+
+    /// Wrap the prompt into a single user message
+    pub fn into_chat_request(self) -> crate::Result<ChatRequest> {
+        let prompt = match self.prompt {
+            Prompt::One(prompt) => prompt,
+            Prompt::Many(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+            Prompt::Many(_) => {
+                return Err(Error::InvalidParameter(
+                    "prompt",
+                    "Only a single prompt is supported".to_owned(),
+                ))
+            }
+        };
+
+        Ok(ChatRequest::builder()
+            .model(self.model)
+            .messages(vec![Message::builder()
+                .role(Role::User)
+                .content(Content::Text(prompt))
+                .build()])
+            .stream(self.stream)
+            .stream_options(self.stream_options)
+            .user(self.user)
+            .stop(self.stop)
+            .max_tokens(self.max_tokens)
+            .build())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone, TypedBuilder)]
 pub struct Message {
     #[builder(default, setter(into))]
     role: Option<Role>,
 
     #[builder(default, setter(into))]
     content: Option<Content>,
+
+    #[builder(default, setter(into))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+
+    #[builder(default, setter(into))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+
+    #[builder(default, setter(into))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+impl Message {
+    pub fn role(&self) -> Option<&Role> {
+        self.role.as_ref()
+    }
+
+    pub fn content(&self) -> Option<&Content> {
+        self.content.as_ref()
+    }
+
+    /// Turn a tool call or a tool result into plain text, the upstream only
+    /// knows user and assistant turns
+    pub fn render_tools(&mut self) {
+        match self.role {
+            Some(Role::Tool) => {
+                let content = self
+                    .content
+                    .take()
+                    .map(Content::into_text)
+                    .unwrap_or_default();
+                let id = self.tool_call_id.take().filter(|id| !id.is_empty());
+                let name = self.name.take();
+                self.role = Some(Role::User);
+                self.content = Some(Content::Text(tools::render_result(
+                    id.as_deref(),
+                    name.as_deref(),
+                    &content,
+                )));
+            }
+            Some(Role::Assistant) => {
+                if let Some(calls) = self.tool_calls.take().filter(|calls| !calls.is_empty()) {
+                    let mut text = self
+                        .content
+                        .take()
+                        .map(Content::into_text)
+                        .unwrap_or_default();
+                    if !text.is_empty() {
+                        text.push_str("\n\n");
+                    }
+                    text.push_str(&tools::render_calls(&calls));
+                    self.content = Some(Content::Text(text));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Tool definition, only functions are supported
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// `none`/`auto`/`required` or a specific function
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ToolChoice {
+    Mode(String),
+    Function { function: FunctionName },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+pub struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, TypedBuilder)]
+pub struct ToolCall {
+    /// Position of the call, only set in stream chunks
+    #[builder(default, setter(into))]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub id: String,
+    #[builder(default = "function".to_owned())]
+    #[serde(rename = "type")]
+    pub r#type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments
+    pub arguments: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 pub enum Content {
     Text(String),
     Vec(Vec<ContentItem>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Content {
+    /// Text parts of the content
+    pub fn texts(&self) -> impl Iterator<Item = &str> {
+        let (text, items) = match self {
+            Content::Text(text) => (Some(text.as_str()), &[][..]),
+            Content::Vec(vec) => (None, vec.as_slice()),
+        };
+        text.into_iter()
+            .chain(items.iter().map(|item| item.text.as_str()))
+    }
+
+    /// Join all text parts into a single string
+    pub fn into_text(self) -> String {
+        match self {
+            Content::Text(text) => text,
+            Content::Vec(vec) => vec
+                .into_iter()
+                .map(|item| item.text)
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ContentItem {
     #[serde(rename = "type")]
     r#type: String,
     text: String,
 }
 
-fn deserialize_model<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let model = String::deserialize(deserializer)?;
-    let model = match model.as_str() {
-        "claude-3-haiku" => "claude-3-haiku-20240307",
-        "llama-3.3-70b" => "meta-llama/Llama-3.3-70B-Instruct-Turbo",
-        "mistral-small-3" => "mistralai/Mistral-Small-24B-Instruct-2501",
-        "o3-mini" => "o3-mini",
-        _ => "gpt-4o-mini",
-    };
-
-    Ok(model.to_owned())
-}
-
-fn deserialize_message<'de, D>(deserializer: D) -> Result<Vec<Message>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let message: Vec<Message> = Vec::deserialize(deserializer)?;
-    let mut compression_message = Vec::with_capacity(message.len());
-    for mut message in message {
+/// Forward the real role/content sequence.
+///
+/// The upstream only accepts `user` and `assistant` turns, so system prompts
+/// are folded into the next user message, and consecutive messages with the
+/// same role are merged.
+fn into_turns(messages: Vec<Message>) -> Vec<Message> {
+    let mut turns: Vec<Message> = Vec::with_capacity(messages.len());
+    let mut system = Vec::new();
+
+    for message in messages {
+        let (Some(role), Some(content)) = (message.role, message.content) else {
+            continue;
+        };
+
+        let mut text = content.into_text();
+        let role = match role {
+            Role::System => {
+                system.push(text);
+                continue;
+            }
+            Role::User => {
+                if !system.is_empty() {
+                    system.push(text);
+                    text = system.join("\n\n");
+                    system.clear();
+                }
+                Role::User
+            }
+            Role::Assistant => Role::Assistant,
+            // Rendered as user turns by `apply_tools`
+            Role::Tool => Role::User,
+        };
+
+        match turns.last_mut() {
+            Some(Message {
+                role: Some(last_role),
+                content: Some(Content::Text(last_text)),
+                ..
+            }) if *last_role == role => {
+                last_text.push_str("\n\n");
+                last_text.push_str(&text);
+            }
+            _ => turns.push(
+                Message::builder()
+                    .role(role)
+                    .content(Content::Text(text))
+                    .build(),
+            ),
+        }
+    }
+
+    // Trailing system prompts without a following user message
+    if !system.is_empty() {
+        turns.push(
+            Message::builder()
+                .role(Role::User)
+                .content(Content::Text(system.join("\n\n")))
+                .build(),
+        );
+    }
+
+    turns
+}
+
+/// Collapse the conversation into one user message of `role:text;` lines
+fn into_flatten(messages: Vec<Message>) -> Vec<Message> {
+    let mut compression_message = Vec::with_capacity(messages.len());
+    for mut message in messages {
         if let (Some(role), Some(msg)) = (message.role.as_mut(), message.content) {
             if matches!(role, Role::System) {
                 *role = Role::User;
@@ -110,10 +620,10 @@ where
         }
     }
 
-    Ok(vec![Message::builder()
+    vec![Message::builder()
         .role(Role::User)
         .content(Content::Text(compression_message.join("\n")))
-        .build()])
+        .build()]
 }
 
 // ==================== Duck APi Response Body ====================
@@ -126,10 +636,20 @@ pub struct DuckChatCompletion {
     pub model: Option<String>,
 }
 
-fn default_id() -> String {
+pub fn default_id() -> String {
     "chatcmpl-123".to_owned()
 }
 
+/// Random object id, `{prefix}_` followed by 24 alphanumerics
+pub fn random_id(prefix: &str) -> String {
+    let id = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect::<String>();
+    format!("{prefix}_{id}")
+}
+
 // ==================== Response Body ====================
 #[derive(Serialize, TypedBuilder)]
 pub struct ChatCompletion<'a> {
@@ -170,10 +690,53 @@ pub struct Choice {
 }
 
 #[derive(Serialize, TypedBuilder)]
+pub struct TextCompletion<'a> {
+    #[builder(setter(into))]
+    id: String,
+
+    #[builder(default = "text_completion")]
+    object: &'static str,
+
+    created: u64,
+
+    model: &'a str,
+
+    choices: Vec<TextChoice>,
+
+    #[builder(default, setter(into))]
+    usage: Option<Usage>,
+}
+
+#[derive(Serialize, TypedBuilder)]
+pub struct TextChoice {
+    #[builder(setter(into))]
+    text: String,
+
+    #[builder(default)]
+    index: usize,
+
+    #[builder(default)]
+    logprobs: Option<String>,
+
+    #[builder(setter(into))]
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Serialize, Clone, Copy)]
 pub struct Usage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl Usage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -182,17 +745,17 @@ pub struct Pong {
 }
 
 #[derive(Serialize, TypedBuilder)]
-pub struct Models {
+pub struct Models<'a> {
     object: &'static str,
-    data: &'static [ModelData; 5],
+    data: Vec<ModelData<'a>>,
 }
 
 #[derive(Serialize, Deserialize, TypedBuilder)]
-pub struct ModelData {
-    id: &'static str,
+pub struct ModelData<'a> {
+    id: &'a str,
     #[builder(default = "model")]
-    object: &'static str,
+    object: &'a str,
     #[builder(default = 1686935002)]
     created: i64,
-    owned_by: &'static str,
+    owned_by: &'a str,
 }
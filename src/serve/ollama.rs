@@ -0,0 +1,512 @@
+//! Ollama API front-end.
+//!
+//! `/api/chat` and `/api/generate` are translated into chat requests and go
+//! through the same upstream flow, replies are rendered as Ollama responses
+//! and streamed as newline-delimited JSON.
+
+use super::{
+    auth::ApiKey,
+    catalog::ModelCatalog,
+    model::{
+        self, Content, FunctionCall, JsonSchema, Message, ResponseFormat, Role, Stop, Tool,
+        ToolCall, Usage,
+    },
+    route::process::{Completion, Delta, Reply},
+};
+use crate::{config::ModelConfig, error::Error};
+use axum::{
+    body::Body,
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Modification time reported for the configured models
+const MODIFIED_AT: u64 = 1686935002;
+
+// ==================== Request Body ====================
+#[derive(Deserialize)]
+pub struct ChatRequest {
+    model: String,
+
+    #[serde(default)]
+    messages: Vec<InputMessage>,
+
+    #[serde(default)]
+    tools: Option<Vec<Tool>>,
+
+    #[serde(default)]
+    format: Option<Format>,
+
+    #[serde(default)]
+    options: Option<Options>,
+
+    #[serde(default)]
+    stream: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct GenerateRequest {
+    model: String,
+
+    #[serde(default)]
+    prompt: String,
+
+    #[serde(default)]
+    system: Option<String>,
+
+    #[serde(default)]
+    format: Option<Format>,
+
+    #[serde(default)]
+    options: Option<Options>,
+
+    #[serde(default)]
+    stream: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ShowRequest {
+    #[serde(alias = "name")]
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct InputMessage {
+    role: Role,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Option<Vec<OllamaToolCall>>,
+    #[serde(default)]
+    tool_name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunction,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OllamaFunction {
+    name: String,
+    arguments: Value,
+}
+
+/// `json` or a JSON schema
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Format {
+    Named(String),
+    Schema(Value),
+}
+
+/// Model options, only the ones enforced by the proxy are read
+#[derive(Deserialize)]
+struct Options {
+    #[serde(default)]
+    num_predict: Option<i64>,
+    #[serde(default)]
+    stop: Option<Vec<String>>,
+}
+
+/// Ollama endpoint a reply is rendered for
+#[derive(Clone, Copy)]
+pub enum Api {
+    Chat,
+    Generate,
+}
+
+/// Translated Ollama request
+pub struct Request {
+    pub api: Api,
+    /// Model name as requested, echoed in the responses
+    pub model: String,
+    pub stream: bool,
+    pub body: model::ChatRequest,
+}
+
+impl ChatRequest {
+    pub fn into_request(self) -> crate::Result<Request> {
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|message| {
+                let tool_calls = message.tool_calls.map(|calls| {
+                    calls
+                        .into_iter()
+                        .map(|call| {
+                            ToolCall::builder()
+                                .id(String::new())
+                                .function(FunctionCall {
+                                    name: call.function.name,
+                                    arguments: call.function.arguments.to_string(),
+                                })
+                                .build()
+                        })
+                        .collect::<Vec<_>>()
+                });
+                Message::builder()
+                    .role(message.role)
+                    .content(Content::Text(message.content))
+                    .tool_calls(tool_calls)
+                    .name(message.tool_name)
+                    .build()
+            })
+            .collect();
+
+        let (max_tokens, stop) = options(self.options);
+        Ok(Request {
+            api: Api::Chat,
+            stream: self.stream.unwrap_or(true),
+            body: model::ChatRequest::builder()
+                .model(model_name(&self.model))
+                .messages(messages)
+                .stream(self.stream)
+                .stop(stop)
+                .max_tokens(max_tokens)
+                .tools(self.tools)
+                .response_format(response_format(self.format)?)
+                .build(),
+            model: self.model,
+        })
+    }
+}
+
+impl GenerateRequest {
+    pub fn into_request(self) -> crate::Result<Request> {
+        let system = self
+            .system
+            .filter(|system| !system.is_empty())
+            .map(|system| {
+                Message::builder()
+                    .role(Role::System)
+                    .content(Content::Text(system))
+                    .build()
+            });
+        let messages = system
+            .into_iter()
+            .chain(std::iter::once(
+                Message::builder()
+                    .role(Role::User)
+                    .content(Content::Text(self.prompt))
+                    .build(),
+            ))
+            .collect();
+
+        let (max_tokens, stop) = options(self.options);
+        Ok(Request {
+            api: Api::Generate,
+            stream: self.stream.unwrap_or(true),
+            body: model::ChatRequest::builder()
+                .model(model_name(&self.model))
+                .messages(messages)
+                .stream(self.stream)
+                .stop(stop)
+                .max_tokens(max_tokens)
+                .response_format(response_format(self.format)?)
+                .build(),
+            model: self.model,
+        })
+    }
+}
+
+/// Model name without the `:latest` tag added by Ollama clients
+fn model_name(model: &str) -> &str {
+    model.strip_suffix(":latest").unwrap_or(model)
+}
+
+/// Token limit and stop sequences of the options, a negative `num_predict`
+/// means no limit
+fn options(options: Option<Options>) -> (Option<usize>, Option<Stop>) {
+    let Some(options) = options else {
+        return (None, None);
+    };
+    let max_tokens = options
+        .num_predict
+        .filter(|num_predict| *num_predict > 0)
+        .map(|num_predict| num_predict as usize);
+    (max_tokens, options.stop.map(Stop::Many))
+}
+
+fn response_format(format: Option<Format>) -> crate::Result<Option<ResponseFormat>> {
+    match format {
+        None => Ok(None),
+        Some(Format::Named(name)) => match name.as_str() {
+            "" => Ok(None),
+            "json" => Ok(Some(ResponseFormat::JsonObject)),
+            _ => Err(Error::InvalidParameter(
+                "format",
+                format!("Invalid format '{name}', expected 'json' or a JSON schema"),
+            )),
+        },
+        Some(Format::Schema(schema)) => Ok(Some(ResponseFormat::JsonSchema {
+            json_schema: JsonSchema {
+                name: "response".to_owned(),
+                description: None,
+                schema: Some(schema),
+                strict: None,
+            },
+        })),
+    }
+}
+
+// ==================== Response Body ====================
+#[derive(Serialize)]
+struct Chunk<'a> {
+    model: &'a str,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<OutputMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    done: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    done_reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_duration: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_eval_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eval_count: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct OutputMessage {
+    role: &'static str,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(Serialize)]
+pub struct Tags<'a> {
+    models: Vec<TagModel<'a>>,
+}
+
+#[derive(Serialize)]
+struct TagModel<'a> {
+    name: &'a str,
+    model: &'a str,
+    modified_at: String,
+    size: u64,
+    digest: String,
+    details: Details<'a>,
+}
+
+#[derive(Serialize)]
+struct Details<'a> {
+    format: &'static str,
+    family: &'a str,
+    parameter_size: &'static str,
+    quantization_level: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct Show<'a> {
+    modelfile: String,
+    parameters: &'static str,
+    template: &'static str,
+    details: Details<'a>,
+    model_info: serde_json::Map<String, Value>,
+    capabilities: [&'static str; 2],
+    modified_at: String,
+}
+
+impl Api {
+    fn chunk(
+        self,
+        model: &str,
+        text: String,
+        tool_calls: Option<Vec<OllamaToolCall>>,
+    ) -> Chunk<'_> {
+        let (message, response) = match self {
+            Api::Chat => (
+                Some(OutputMessage {
+                    role: "assistant",
+                    content: text,
+                    tool_calls,
+                }),
+                None,
+            ),
+            Api::Generate => (None, Some(text)),
+        };
+
+        Chunk {
+            model,
+            created_at: now(),
+            message,
+            response,
+            done: false,
+            done_reason: None,
+            total_duration: None,
+            prompt_eval_count: None,
+            eval_count: None,
+        }
+    }
+}
+
+impl Chunk<'_> {
+    /// Final chunk with the reply statistics
+    fn finish(mut self, finish_reason: &'static str, usage: Usage, started: Instant) -> Self {
+        self.done = true;
+        self.done_reason = Some(match finish_reason {
+            "length" => "length",
+            _ => "stop",
+        });
+        self.total_duration = Some(started.elapsed().as_nanos());
+        self.prompt_eval_count = Some(usage.prompt_tokens);
+        self.eval_count = Some(usage.completion_tokens);
+        self
+    }
+}
+
+/// Newline-delimited JSON chunks of a reply
+pub fn stream(api: Api, model: String, started: Instant, reply: Reply) -> Response {
+    let deltas = reply.deltas();
+    let lines = async_stream::stream! {
+        futures_util::pin_mut!(deltas);
+        while let Some(delta) = deltas.next().await {
+            let chunk = match delta {
+                Delta::Start { .. } => continue,
+                Delta::Text(text) => api.chunk(&model, text, None),
+                Delta::ToolCalls(calls) => {
+                    api.chunk(&model, String::new(), Some(tool_calls(&calls)))
+                }
+                // The stream ends with the error line
+                Delta::Error(err) => {
+                    yield line(&error_body(&err));
+                    break;
+                }
+                Delta::Finish {
+                    finish_reason,
+                    usage,
+                    ..
+                } => api
+                    .chunk(&model, String::new(), None)
+                    .finish(finish_reason, usage, started),
+            };
+            yield line(&chunk);
+        }
+    };
+
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+/// Single response of a whole reply
+pub fn response(api: Api, model: &str, started: Instant, completion: Completion) -> Response {
+    let usage = completion.usage();
+    let finish_reason = completion.finish_reason();
+    let calls = completion.tool_calls().map(tool_calls);
+    let text = match calls {
+        Some(_) => String::new(),
+        None => completion.into_text(),
+    };
+
+    Json(
+        api.chunk(model, text, calls)
+            .finish(finish_reason, usage, started),
+    )
+    .into_response()
+}
+
+/// Ollama error response
+pub fn error_response(err: Error) -> Response {
+    let (status, headers) = (err.to_openai().0, err.headers());
+    (status, headers, Json(error_body(&err))).into_response()
+}
+
+/// Configured models
+pub fn tags<'a>(models: impl Iterator<Item = &'a ModelConfig>) -> Tags<'a> {
+    Tags {
+        models: models
+            .map(|model| TagModel {
+                name: &model.id,
+                model: &model.id,
+                modified_at: modified_at(),
+                size: 0,
+                digest: format!("{:x}", Sha256::digest(model.id.as_bytes())),
+                details: details(model),
+            })
+            .collect(),
+    }
+}
+
+/// Details of a model, models the API key may not use are not found
+pub fn show<'a>(
+    catalog: &'a ModelCatalog,
+    key: Option<&ApiKey>,
+    request: &ShowRequest,
+) -> crate::Result<Show<'a>> {
+    let model = catalog
+        .get(model_name(&request.model))
+        .filter(|model| key.map_or(true, |key| key.allows(model)))
+        .ok_or_else(|| Error::ModelNotFound(request.model.clone()))?;
+
+    Ok(Show {
+        modelfile: format!("FROM {}\n", model.upstream),
+        parameters: "",
+        template: "{{ .Prompt }}",
+        details: details(model),
+        model_info: Default::default(),
+        capabilities: ["completion", "tools"],
+        modified_at: modified_at(),
+    })
+}
+
+fn details(model: &ModelConfig) -> Details<'_> {
+    Details {
+        format: "api",
+        family: &model.owned_by,
+        parameter_size: "",
+        quantization_level: "",
+    }
+}
+
+fn tool_calls(calls: &[ToolCall]) -> Vec<OllamaToolCall> {
+    calls
+        .iter()
+        .map(|call| OllamaToolCall {
+            function: OllamaFunction {
+                name: call.function.name.clone(),
+                arguments: serde_json::from_str(&call.function.arguments)
+                    .unwrap_or_else(|_| Value::Object(Default::default())),
+            },
+        })
+        .collect()
+}
+
+fn error_body(err: &Error) -> ErrorResponse {
+    let (_, error) = err.to_openai();
+    ErrorResponse {
+        error: error.message().to_owned(),
+    }
+}
+
+fn line<T: Serialize>(value: &T) -> Result<String, serde_json::Error> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_millis(SystemTime::now()).to_string()
+}
+
+fn modified_at() -> String {
+    humantime::format_rfc3339(UNIX_EPOCH + Duration::from_secs(MODIFIED_AT)).to_string()
+}
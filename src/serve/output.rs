@@ -0,0 +1,146 @@
+use crate::config::Tokenizer;
+
+/// Proxy side enforcement of `stop` and `max_tokens` on the generated text.
+///
+/// Text that may be the start of a stop sequence is held back until the next
+/// chunk tells whether it matches, so that a stop sequence split across
+/// upstream chunks is never sent.
+pub struct OutputLimit {
+    stop: Vec<String>,
+    max_tokens: Option<usize>,
+    tokenizer: Tokenizer,
+
+    /// Text sent so far
+    text: String,
+
+    /// Upper bound of the tokens of `text`, counted per chunk
+    tokens: usize,
+
+    /// Text held back, may be the start of a stop sequence
+    held: String,
+
+    finish_reason: Option<&'static str>,
+
+    /// Stop sequence that ended the text
+    stop_sequence: Option<String>,
+}
+
+impl OutputLimit {
+    pub fn new(stop: Vec<String>, max_tokens: Option<usize>, tokenizer: Tokenizer) -> Self {
+        Self {
+            stop: stop.into_iter().filter(|stop| !stop.is_empty()).collect(),
+            max_tokens,
+            tokenizer,
+            text: String::new(),
+            tokens: 0,
+            held: String::new(),
+            finish_reason: None,
+            stop_sequence: None,
+        }
+    }
+
+    /// Feed generated text, returns the part that may be sent
+    pub fn push(&mut self, delta: &str) -> String {
+        if self.is_finished() {
+            return String::new();
+        }
+
+        self.held.push_str(delta);
+
+        let matched = self
+            .stop
+            .iter()
+            .filter_map(|stop| Some((self.held.find(stop.as_str())?, stop)))
+            .min_by_key(|(index, _)| *index);
+        let out = match matched {
+            Some((index, stop)) => {
+                self.stop_sequence = Some(stop.clone());
+                self.held.truncate(index);
+                self.finish_reason = Some("stop");
+                std::mem::take(&mut self.held)
+            }
+            None => {
+                let keep = self.partial_stop_len();
+                let out = self.held[..self.held.len() - keep].to_owned();
+                self.held.drain(..self.held.len() - keep);
+                out
+            }
+        };
+
+        self.emit(out)
+    }
+
+    /// Flush the held back text once the upstream is done
+    pub fn finish(&mut self) -> String {
+        if self.is_finished() {
+            return String::new();
+        }
+
+        let held = std::mem::take(&mut self.held);
+        self.emit(held)
+    }
+
+    /// Whether a stop sequence or the token limit was reached, the upstream
+    /// response should then be dropped
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+
+    /// `stop` or `length` once a limit was reached
+    #[inline]
+    pub fn finish_reason(&self) -> Option<&'static str> {
+        self.finish_reason
+    }
+
+    /// Stop sequence that ended the text, if any
+    #[inline]
+    pub fn stop_sequence(&self) -> Option<&str> {
+        self.stop_sequence.as_deref()
+    }
+
+    /// Text sent so far
+    #[inline]
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Cut the text at the token limit
+    fn emit(&mut self, mut out: String) -> String {
+        if let Some(max_tokens) = self.max_tokens {
+            // Tokens counted per chunk are never fewer than the tokens of the
+            // whole text, the exact count is only needed near the limit
+            self.tokens += self.tokenizer.count(&out);
+            if self.tokens >= max_tokens {
+                let text = format!("{}{out}", self.text);
+                if let Some(truncated) = self.tokenizer.truncate(&text, max_tokens) {
+                    out = truncated
+                        .get(self.text.len()..)
+                        .unwrap_or_default()
+                        .to_owned();
+                    self.held.clear();
+                    self.finish_reason.get_or_insert("length");
+                }
+                self.tokens = self.tokenizer.count(&text);
+            }
+        }
+
+        self.text.push_str(&out);
+        out
+    }
+
+    /// Length of the longest suffix of the held text that starts a stop sequence
+    fn partial_stop_len(&self) -> usize {
+        self.stop
+            .iter()
+            .flat_map(|stop| {
+                stop.char_indices()
+                    .skip(1)
+                    .map(|(index, _)| &stop[..index])
+                    .filter(|prefix| self.held.ends_with(prefix))
+                    .map(str::len)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
@@ -0,0 +1,926 @@
+//! OpenAI Responses API front-end.
+//!
+//! Input items are translated into chat messages and go through the same
+//! upstream flow, replies are rendered as response objects and semantic SSE
+//! events. Conversations are kept in memory so that a later request can
+//! continue them with `previous_response_id`.
+
+use super::{
+    model::{
+        random_id, ChatRequest, Content, FunctionCall, FunctionDefinition, FunctionName,
+        JsonSchema, Message, ResponseFormat, Role, Tool, ToolCall, ToolChoice, Usage,
+    },
+    route::process::{Completion, Delta, Reply},
+};
+use crate::{config::ResponsesConfig, error::Error};
+use axum::{
+    response::{sse::Event, IntoResponse, Response, Sse},
+    Json,
+};
+use futures_util::StreamExt;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// ==================== Request Body ====================
+#[derive(Deserialize)]
+pub struct ResponsesRequest {
+    model: String,
+
+    input: Input,
+
+    #[serde(default)]
+    instructions: Option<String>,
+
+    #[serde(default)]
+    stream: Option<bool>,
+
+    #[serde(default)]
+    max_output_tokens: Option<usize>,
+
+    #[serde(default)]
+    tools: Option<Vec<InputTool>>,
+
+    #[serde(default)]
+    tool_choice: Option<InputToolChoice>,
+
+    #[serde(default)]
+    text: Option<TextConfig>,
+
+    #[serde(default)]
+    store: Option<bool>,
+
+    #[serde(default)]
+    previous_response_id: Option<String>,
+
+    #[serde(default)]
+    user: Option<String>,
+
+    #[serde(default)]
+    metadata: Option<Map<String, Value>>,
+}
+
+/// Input, a user message or a list of items
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Input {
+    Text(String),
+    Items(Vec<InputItem>),
+}
+
+/// Input item, messages may omit their `type`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputItem {
+    Typed(TypedItem),
+    Message(InputMessage),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TypedItem {
+    Message(InputMessage),
+    FunctionCall {
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+    FunctionCallOutput {
+        call_id: String,
+        output: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct InputMessage {
+    role: InputRole,
+    content: InputContent,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InputRole {
+    User,
+    Assistant,
+    System,
+    Developer,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    InputText {
+        text: String,
+    },
+    OutputText {
+        text: String,
+    },
+    /// Images and files, the upstream only takes text
+    #[serde(other)]
+    Unsupported,
+}
+
+/// Tool definition, only `function` tools are supported
+#[derive(Deserialize)]
+struct InputTool {
+    #[serde(rename = "type")]
+    r#type: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    parameters: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputToolChoice {
+    Mode(String),
+    Function { name: String },
+}
+
+#[derive(Deserialize)]
+struct TextConfig {
+    #[serde(default)]
+    format: Option<TextFormat>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TextFormat {
+    Text,
+    JsonObject,
+    JsonSchema {
+        name: String,
+        #[serde(default)]
+        description: Option<String>,
+        #[serde(default)]
+        schema: Option<Value>,
+        #[serde(default)]
+        strict: Option<bool>,
+    },
+}
+
+impl ResponsesRequest {
+    pub fn stream(&self) -> bool {
+        self.stream.unwrap_or_default()
+    }
+
+    /// Translate into a chat request continuing the stored conversation of
+    /// `previous_response_id`, along with the response to fill
+    pub async fn into_turn(
+        self,
+        store: &Arc<ResponseStore>,
+        owner: Option<String>,
+    ) -> crate::Result<(Turn, ChatRequest)> {
+        let mut conversation = match self.previous_response_id {
+            Some(ref id) => store.get(id, owner.as_deref()).await?.messages.clone(),
+            None => Vec::new(),
+        };
+        match self.input {
+            Input::Text(text) => conversation.push(text_message(Role::User, text)),
+            Input::Items(items) => push_items(items, &mut conversation)?,
+        }
+
+        // Instructions only apply to this response, they are not carried over
+        // by `previous_response_id`
+        let mut messages = Vec::with_capacity(conversation.len() + 1);
+        if let Some(ref instructions) = self.instructions {
+            messages.push(text_message(Role::System, instructions.clone()));
+        }
+        messages.extend(conversation.iter().cloned());
+
+        let tools = self
+            .tools
+            .map(|tools| {
+                tools
+                    .into_iter()
+                    .map(InputTool::into_tool)
+                    .collect::<crate::Result<Vec<_>>>()
+            })
+            .transpose()?;
+        let tool_choice = self.tool_choice.map(|choice| match choice {
+            InputToolChoice::Mode(mode) => ToolChoice::Mode(mode),
+            InputToolChoice::Function { name } => ToolChoice::Function {
+                function: FunctionName { name },
+            },
+        });
+        let response_format = self
+            .text
+            .and_then(|text| text.format)
+            .map(|format| match format {
+                TextFormat::Text => ResponseFormat::Text,
+                TextFormat::JsonObject => ResponseFormat::JsonObject,
+                TextFormat::JsonSchema {
+                    name,
+                    description,
+                    schema,
+                    strict,
+                } => ResponseFormat::JsonSchema {
+                    json_schema: JsonSchema {
+                        name,
+                        description,
+                        schema,
+                        strict,
+                    },
+                },
+            });
+
+        let stored = self.store.unwrap_or(true) && store.enabled;
+        let turn = Turn {
+            response: ResponseObject {
+                id: random_id("resp"),
+                object: "response",
+                created_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                status: "in_progress",
+                error: None,
+                incomplete_details: None,
+                instructions: self.instructions,
+                max_output_tokens: self.max_output_tokens,
+                model: self.model.clone(),
+                output: Vec::new(),
+                parallel_tool_calls: true,
+                previous_response_id: self.previous_response_id,
+                store: stored,
+                usage: None,
+                user: self.user.clone(),
+                metadata: self.metadata.unwrap_or_default(),
+            },
+            conversation: if stored { conversation } else { Vec::new() },
+            owner,
+            store: stored.then(|| store.clone()),
+        };
+        let body = ChatRequest::builder()
+            .model(self.model)
+            .messages(messages)
+            .stream(self.stream)
+            .user(self.user)
+            .max_tokens(self.max_output_tokens)
+            .tools(tools)
+            .tool_choice(tool_choice)
+            .response_format(response_format)
+            .build();
+
+        Ok((turn, body))
+    }
+}
+
+impl InputMessage {
+    fn into_message(self) -> crate::Result<Message> {
+        let role = match self.role {
+            InputRole::User => Role::User,
+            InputRole::Assistant => Role::Assistant,
+            InputRole::System | InputRole::Developer => Role::System,
+        };
+        let text = match self.content {
+            InputContent::Text(text) => text,
+            InputContent::Parts(parts) => parts
+                .into_iter()
+                .map(|part| match part {
+                    ContentPart::InputText { text } | ContentPart::OutputText { text } => Ok(text),
+                    ContentPart::Unsupported => Err(Error::InvalidParameter(
+                        "input",
+                        "Only input_text and output_text content parts are supported".to_owned(),
+                    )),
+                })
+                .collect::<crate::Result<Vec<_>>>()?
+                .join("\n"),
+        };
+        Ok(text_message(role, text))
+    }
+}
+
+impl InputTool {
+    fn into_tool(self) -> crate::Result<Tool> {
+        match (self.r#type.as_str(), self.name) {
+            ("function", Some(name)) => Ok(Tool {
+                r#type: "function".to_owned(),
+                function: FunctionDefinition {
+                    name,
+                    description: self.description,
+                    parameters: self.parameters,
+                },
+            }),
+            (r#type, _) => Err(Error::InvalidParameter(
+                "tools",
+                format!("Unsupported tool '{type}', only named function tools are supported"),
+            )),
+        }
+    }
+}
+
+/// Append the chat messages of input items, consecutive function calls make
+/// a single assistant message
+fn push_items(items: Vec<InputItem>, messages: &mut Vec<Message>) -> crate::Result<()> {
+    let mut calls = Vec::new();
+    for item in items {
+        let message = match item {
+            InputItem::Typed(TypedItem::FunctionCall {
+                call_id,
+                name,
+                arguments,
+            }) => {
+                calls.push(
+                    ToolCall::builder()
+                        .id(call_id)
+                        .function(FunctionCall { name, arguments })
+                        .build(),
+                );
+                continue;
+            }
+            InputItem::Typed(TypedItem::FunctionCallOutput { call_id, output }) => {
+                Message::builder()
+                    .role(Role::Tool)
+                    .content(Content::Text(output))
+                    .tool_call_id(call_id)
+                    .build()
+            }
+            InputItem::Typed(TypedItem::Message(message)) | InputItem::Message(message) => {
+                message.into_message()?
+            }
+        };
+        push_calls(&mut calls, messages);
+        messages.push(message);
+    }
+    push_calls(&mut calls, messages);
+    Ok(())
+}
+
+fn push_calls(calls: &mut Vec<ToolCall>, messages: &mut Vec<Message>) {
+    if !calls.is_empty() {
+        messages.push(
+            Message::builder()
+                .role(Role::Assistant)
+                .content(Content::Text(String::new()))
+                .tool_calls(std::mem::take(calls))
+                .build(),
+        );
+    }
+}
+
+fn text_message(role: Role, text: String) -> Message {
+    Message::builder()
+        .role(role)
+        .content(Content::Text(text))
+        .build()
+}
+
+// ==================== Response Store ====================
+/// Stored conversations by response id, only visible to the API key that
+/// created them
+pub struct ResponseStore {
+    enabled: bool,
+    responses: Cache<String, Arc<StoredResponse>>,
+}
+
+/// Conversation of a response, without its instructions
+struct StoredResponse {
+    owner: Option<String>,
+    messages: Vec<Message>,
+
+    /// Serialized size of the messages (bytes), every response of a chain
+    /// holds the whole conversation so far
+    size: u32,
+}
+
+impl StoredResponse {
+    fn new(owner: Option<String>, messages: Vec<Message>) -> Self {
+        let size = serde_json::to_vec(&messages).map_or(0, |data| data.len());
+        Self {
+            owner,
+            messages,
+            size: u32::try_from(size).unwrap_or(u32::MAX),
+        }
+    }
+}
+
+impl ResponseStore {
+    pub fn new(config: &ResponsesConfig) -> Self {
+        Self {
+            enabled: config.store,
+            responses: Cache::builder()
+                .weigher(|_, response: &Arc<StoredResponse>| response.size)
+                .max_capacity(config.max_size.saturating_mul(1024 * 1024))
+                .time_to_idle(Duration::from_secs(config.ttl))
+                .build(),
+        }
+    }
+
+    async fn get(&self, id: &str, owner: Option<&str>) -> crate::Result<Arc<StoredResponse>> {
+        self.responses
+            .get(id)
+            .await
+            .filter(|response| response.owner.as_deref() == owner)
+            .ok_or_else(|| {
+                Error::InvalidParameter(
+                    "previous_response_id",
+                    format!("Previous response with id '{id}' not found."),
+                )
+            })
+    }
+}
+
+// ==================== Response Body ====================
+#[derive(Serialize)]
+struct ResponseObject {
+    id: String,
+    object: &'static str,
+    created_at: u64,
+    status: &'static str,
+    error: Option<ResponseError>,
+    incomplete_details: Option<IncompleteDetails>,
+    instructions: Option<String>,
+    max_output_tokens: Option<usize>,
+    model: String,
+    output: Vec<OutputItem>,
+    parallel_tool_calls: bool,
+    previous_response_id: Option<String>,
+    store: bool,
+    usage: Option<ResponseUsage>,
+    user: Option<String>,
+    metadata: Map<String, Value>,
+}
+
+#[derive(Serialize)]
+struct ResponseError {
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct IncompleteDetails {
+    reason: &'static str,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputItem {
+    Message {
+        id: String,
+        status: &'static str,
+        role: &'static str,
+        content: Vec<OutputContent>,
+    },
+    FunctionCall {
+        id: String,
+        call_id: String,
+        name: String,
+        arguments: String,
+        status: &'static str,
+    },
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputContent {
+    OutputText {
+        text: String,
+        annotations: Vec<Value>,
+    },
+}
+
+#[derive(Serialize)]
+struct ResponseUsage {
+    input_tokens: usize,
+    input_tokens_details: InputTokensDetails,
+    output_tokens: usize,
+    output_tokens_details: OutputTokensDetails,
+    total_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct InputTokensDetails {
+    cached_tokens: usize,
+}
+
+#[derive(Serialize)]
+struct OutputTokensDetails {
+    reasoning_tokens: usize,
+}
+
+/// Semantic SSE events, the event name is the `type` field
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum StreamEvent<'a> {
+    #[serde(rename = "response.created")]
+    Created { response: &'a ResponseObject },
+    #[serde(rename = "response.in_progress")]
+    InProgress { response: &'a ResponseObject },
+    #[serde(rename = "response.output_item.added")]
+    OutputItemAdded {
+        output_index: usize,
+        item: &'a OutputItem,
+    },
+    #[serde(rename = "response.content_part.added")]
+    ContentPartAdded {
+        item_id: &'a str,
+        output_index: usize,
+        content_index: usize,
+        part: &'a OutputContent,
+    },
+    #[serde(rename = "response.output_text.delta")]
+    OutputTextDelta {
+        item_id: &'a str,
+        output_index: usize,
+        content_index: usize,
+        delta: &'a str,
+    },
+    #[serde(rename = "response.output_text.done")]
+    OutputTextDone {
+        item_id: &'a str,
+        output_index: usize,
+        content_index: usize,
+        text: &'a str,
+    },
+    #[serde(rename = "response.content_part.done")]
+    ContentPartDone {
+        item_id: &'a str,
+        output_index: usize,
+        content_index: usize,
+        part: &'a OutputContent,
+    },
+    #[serde(rename = "response.output_item.done")]
+    OutputItemDone {
+        output_index: usize,
+        item: &'a OutputItem,
+    },
+    #[serde(rename = "response.function_call_arguments.delta")]
+    FunctionCallArgumentsDelta {
+        item_id: &'a str,
+        output_index: usize,
+        delta: &'a str,
+    },
+    #[serde(rename = "response.function_call_arguments.done")]
+    FunctionCallArgumentsDone {
+        item_id: &'a str,
+        output_index: usize,
+        arguments: &'a str,
+    },
+    #[serde(rename = "response.completed")]
+    Completed { response: &'a ResponseObject },
+    #[serde(rename = "response.incomplete")]
+    Incomplete { response: &'a ResponseObject },
+    #[serde(rename = "response.failed")]
+    Failed { response: &'a ResponseObject },
+}
+
+type EventResult = Result<Event, axum::Error>;
+
+impl StreamEvent<'_> {
+    /// SSE event numbered by its position in the stream
+    fn into_event(self, sequence_number: &mut usize) -> EventResult {
+        let mut data = serde_json::to_value(&self).map_err(axum::Error::new)?;
+        data["sequence_number"] = Value::from(*sequence_number);
+        *sequence_number += 1;
+
+        let name = data["type"].as_str().unwrap_or_default().to_owned();
+        Event::default()
+            .event(name)
+            .json_data(data)
+            .map_err(axum::Error::new)
+    }
+}
+
+impl OutputContent {
+    fn text(text: String) -> Self {
+        OutputContent::OutputText {
+            text,
+            annotations: Vec::new(),
+        }
+    }
+}
+
+impl From<Usage> for ResponseUsage {
+    fn from(usage: Usage) -> Self {
+        Self {
+            input_tokens: usage.prompt_tokens,
+            input_tokens_details: InputTokensDetails { cached_tokens: 0 },
+            output_tokens: usage.completion_tokens,
+            output_tokens_details: OutputTokensDetails {
+                reasoning_tokens: 0,
+            },
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+/// Response being generated, with the conversation it continues
+pub struct Turn {
+    response: ResponseObject,
+    conversation: Vec<Message>,
+    owner: Option<String>,
+    store: Option<Arc<ResponseStore>>,
+}
+
+impl Turn {
+    /// Response object of a whole reply
+    pub async fn respond(mut self, completion: Completion) -> Response {
+        self.response.model = completion.model().to_owned();
+        let output = match completion.tool_calls() {
+            Some(calls) => calls
+                .iter()
+                .map(|call| function_call(random_id("fc"), call))
+                .collect(),
+            None => vec![OutputItem::Message {
+                id: random_id("msg"),
+                status: "completed",
+                role: "assistant",
+                content: vec![OutputContent::text(completion.text().to_owned())],
+            }],
+        };
+
+        self.finish(completion.finish_reason(), output, completion.usage())
+            .await;
+        Json(self.response).into_response()
+    }
+
+    /// Semantic SSE events of a reply
+    pub fn stream(self, reply: Reply) -> Response {
+        let mut turn = self;
+        turn.response.model = reply.model().to_owned();
+        let deltas = reply.deltas();
+        let sse_stream = async_stream::stream! {
+            let mut sequence = 0;
+            yield StreamEvent::Created { response: &turn.response }.into_event(&mut sequence);
+            yield StreamEvent::InProgress { response: &turn.response }.into_event(&mut sequence);
+
+            let mut output = Vec::new();
+            // Id and text of the message being streamed
+            let mut message: Option<(String, String)> = None;
+            let mut failure = None;
+
+            futures_util::pin_mut!(deltas);
+            while let Some(delta) = deltas.next().await {
+                match delta {
+                    Delta::Start { .. } => {}
+                    Delta::Text(delta) => {
+                        if message.is_none() {
+                            let id = random_id("msg");
+                            for event in open_message(&id, output.len(), &mut sequence) {
+                                yield event;
+                            }
+                            message = Some((id, String::new()));
+                        }
+                        if let Some((ref id, ref mut text)) = message {
+                            text.push_str(&delta);
+                            yield StreamEvent::OutputTextDelta {
+                                item_id: id,
+                                output_index: output.len(),
+                                content_index: 0,
+                                delta: &delta,
+                            }
+                            .into_event(&mut sequence);
+                        }
+                    }
+                    Delta::ToolCalls(calls) => {
+                        if let Some((id, text)) = message.take() {
+                            for event in close_message(id, text, &mut output, &mut sequence) {
+                                yield event;
+                            }
+                        }
+                        for call in calls {
+                            for event in stream_function_call(&call, &mut output, &mut sequence) {
+                                yield event;
+                            }
+                        }
+                    }
+                    Delta::Error(err) => failure = Some(err),
+                    Delta::Finish {
+                        finish_reason,
+                        usage,
+                        ..
+                    } => {
+                        if let Some((id, text)) = message.take() {
+                            for event in close_message(id, text, &mut output, &mut sequence) {
+                                yield event;
+                            }
+                        }
+                        let output = std::mem::take(&mut output);
+                        let event = match failure.take() {
+                            Some(err) => {
+                                turn.fail(&err, output, usage);
+                                StreamEvent::Failed { response: &turn.response }
+                            }
+                            None => {
+                                turn.finish(finish_reason, output, usage).await;
+                                let response = &turn.response;
+                                match response.status {
+                                    "incomplete" => StreamEvent::Incomplete { response },
+                                    _ => StreamEvent::Completed { response },
+                                }
+                            }
+                        };
+                        yield event.into_event(&mut sequence);
+                    }
+                }
+            }
+        };
+
+        Sse::new(sse_stream).into_response()
+    }
+
+    /// Complete the response, its conversation is stored for
+    /// `previous_response_id`
+    async fn finish(&mut self, finish_reason: &str, output: Vec<OutputItem>, usage: Usage) {
+        if finish_reason == "length" {
+            self.response.status = "incomplete";
+            self.response.incomplete_details = Some(IncompleteDetails {
+                reason: "max_output_tokens",
+            });
+        } else {
+            self.response.status = "completed";
+        }
+        self.response.output = output;
+        self.response.usage = Some(usage.into());
+
+        if let Some(store) = self.store.take() {
+            let mut messages = std::mem::take(&mut self.conversation);
+            messages.push(assistant_message(&self.response.output));
+            let stored = StoredResponse::new(self.owner.take(), messages);
+            store
+                .responses
+                .insert(self.response.id.clone(), Arc::new(stored))
+                .await;
+        }
+    }
+
+    /// Fail the response after an upstream failure, nothing is stored
+    fn fail(&mut self, err: &Error, output: Vec<OutputItem>, usage: Usage) {
+        let (_, error) = err.to_openai();
+        self.response.status = "failed";
+        self.response.error = Some(ResponseError {
+            code: error.code().unwrap_or("server_error"),
+            message: error.message().to_owned(),
+        });
+        self.response.output = output;
+        self.response.usage = Some(usage.into());
+    }
+}
+
+/// Events adding a message item and its empty text part
+fn open_message(id: &str, output_index: usize, sequence: &mut usize) -> Vec<EventResult> {
+    let item = OutputItem::Message {
+        id: id.to_owned(),
+        status: "in_progress",
+        role: "assistant",
+        content: Vec::new(),
+    };
+    vec![
+        StreamEvent::OutputItemAdded {
+            output_index,
+            item: &item,
+        }
+        .into_event(sequence),
+        StreamEvent::ContentPartAdded {
+            item_id: id,
+            output_index,
+            content_index: 0,
+            part: &OutputContent::text(String::new()),
+        }
+        .into_event(sequence),
+    ]
+}
+
+/// Events completing the streamed message, which is added to the output
+fn close_message(
+    id: String,
+    text: String,
+    output: &mut Vec<OutputItem>,
+    sequence: &mut usize,
+) -> Vec<EventResult> {
+    let output_index = output.len();
+    let part = OutputContent::text(text.clone());
+    let item = OutputItem::Message {
+        id: id.clone(),
+        status: "completed",
+        role: "assistant",
+        content: vec![part.clone()],
+    };
+    let events = vec![
+        StreamEvent::OutputTextDone {
+            item_id: &id,
+            output_index,
+            content_index: 0,
+            text: &text,
+        }
+        .into_event(sequence),
+        StreamEvent::ContentPartDone {
+            item_id: &id,
+            output_index,
+            content_index: 0,
+            part: &part,
+        }
+        .into_event(sequence),
+        StreamEvent::OutputItemDone {
+            output_index,
+            item: &item,
+        }
+        .into_event(sequence),
+    ];
+    output.push(item);
+    events
+}
+
+/// Events of a function call, sent at once, which is added to the output
+fn stream_function_call(
+    call: &ToolCall,
+    output: &mut Vec<OutputItem>,
+    sequence: &mut usize,
+) -> Vec<EventResult> {
+    let output_index = output.len();
+    let id = random_id("fc");
+    let added = OutputItem::FunctionCall {
+        id: id.clone(),
+        call_id: call.id.clone(),
+        name: call.function.name.clone(),
+        arguments: String::new(),
+        status: "in_progress",
+    };
+    let item = function_call(id.clone(), call);
+    let events = vec![
+        StreamEvent::OutputItemAdded {
+            output_index,
+            item: &added,
+        }
+        .into_event(sequence),
+        StreamEvent::FunctionCallArgumentsDelta {
+            item_id: &id,
+            output_index,
+            delta: &call.function.arguments,
+        }
+        .into_event(sequence),
+        StreamEvent::FunctionCallArgumentsDone {
+            item_id: &id,
+            output_index,
+            arguments: &call.function.arguments,
+        }
+        .into_event(sequence),
+        StreamEvent::OutputItemDone {
+            output_index,
+            item: &item,
+        }
+        .into_event(sequence),
+    ];
+    output.push(item);
+    events
+}
+
+fn function_call(id: String, call: &ToolCall) -> OutputItem {
+    OutputItem::FunctionCall {
+        id,
+        call_id: call.id.clone(),
+        name: call.function.name.clone(),
+        arguments: call.function.arguments.clone(),
+        status: "completed",
+    }
+}
+
+/// Assistant message of the output, stored with the conversation
+fn assistant_message(output: &[OutputItem]) -> Message {
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    for item in output {
+        match item {
+            OutputItem::Message { content, .. } => {
+                for OutputContent::OutputText { text: part, .. } in content {
+                    text.push_str(part);
+                }
+            }
+            OutputItem::FunctionCall {
+                call_id,
+                name,
+                arguments,
+                ..
+            } => calls.push(
+                ToolCall::builder()
+                    .id(call_id.clone())
+                    .function(FunctionCall {
+                        name: name.clone(),
+                        arguments: arguments.clone(),
+                    })
+                    .build(),
+            ),
+        }
+    }
+
+    Message::builder()
+        .role(Role::Assistant)
+        .content(Content::Text(text))
+        .tool_calls((!calls.is_empty()).then_some(calls))
+        .build()
+}
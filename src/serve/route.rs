@@ -1,22 +1,37 @@
 use super::{
-    model::{ChatRequest, ModelData, Models, Pong},
-    AppState,
+    anthropic::{self, MessagesRequest},
+    auth::ApiKey,
+    batch::{self, CreateBatch, ListBatches, ListFiles},
+    client::{InFlight, PoolClient, ORIGIN_API},
+    limit::Permit,
+    metrics::{self, RequestLabels},
+    model::{push_reask, ChatRequest, CompletionRequest, ModelData, Models, Pong},
+    ollama,
+    output::OutputLimit,
+    responses::ResponsesRequest,
+    AppState, Runtime,
 };
-use crate::error::Error;
 use crate::Result;
-use axum::{extract::State, response::Response, Json};
+use crate::{
+    config::{ModelConfig, RetryConfig, Tokenizer},
+    error::Error,
+};
+use axum::{
+    extract::{rejection::JsonRejection, ConnectInfo, Multipart, Path, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
 use axum_extra::{
     extract::WithRejection,
     headers::{authorization::Bearer, Authorization},
     TypedHeader,
 };
-use process::ChatProcess;
-use rquest::{header, Client};
-use std::sync::LazyLock;
+use process::{ChatProcess, Reply};
+use rquest::{header, StatusCode};
+use std::{net::SocketAddr, sync::Arc, time::Instant};
 use tracing::Instrument;
 
-const ORIGIN_API: &str = "https://duckduckgo.com";
-
 pub async fn manual_hello() -> &'static str {
     "DuckDuckGo AI to OpenAI, Developed by penumbra-x. Go to /v1/chat/completions with POST. https://github.com/penumbra-x/duckai"
 }
@@ -28,186 +43,855 @@ pub async fn ping() -> Json<Pong> {
 pub async fn models(
     State(state): State<AppState>,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
-) -> Result<Json<Models>> {
-    state.valid_key(bearer)?;
+) -> Result<Response> {
+    let state = state.load();
+    let key = state.authenticate(bearer.as_ref())?;
 
-    static MODEL_DATA: LazyLock<[ModelData; 5]> = LazyLock::new(|| {
-        [
-            ModelData::builder()
-                .id("gpt-4o-mini")
-                .owned_by("openai")
-                .build(),
-            ModelData::builder()
-                .id("claude-3-haiku")
-                .owned_by("claude")
-                .build(),
-            ModelData::builder()
-                .id("llama-3.3-70b")
-                .owned_by("meta-llama")
-                .build(),
-            ModelData::builder()
-                .id("mistral-small-3")
-                .owned_by("mistral ai")
-                .build(),
+    let data = state
+        .models()
+        .iter()
+        .filter(|model| key.map_or(true, |key| key.allows(model)))
+        .map(|model| {
             ModelData::builder()
-                .id("o3-mini")
-                .owned_by("openai")
-                .build(),
-        ]
-    });
+                .id(&model.id)
+                .owned_by(&model.owned_by)
+                .build()
+        })
+        .collect::<Vec<_>>();
 
-    Ok(Json(
-        Models::builder().object("list").data(&MODEL_DATA).build(),
-    ))
+    Ok(Json(Models::builder().object("list").data(data).build()).into_response())
 }
 
 pub async fn chat_completions(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
     bearer: Option<TypedHeader<Authorization<Bearer>>>,
     WithRejection(Json(body), _): WithRejection<Json<ChatRequest>, Error>,
 ) -> crate::Result<Response> {
-    state.valid_key(bearer)?;
-    let client = state.load_client().await;
-    let token = load_token(&client).await?;
+    validate_stop(&body)?;
+    let stream = body.stream().unwrap_or_default();
+    let include_usage = body.include_usage();
+
+    let token = bearer.as_deref().map(|b| b.token());
+    let (reply, rate_limit) = chat(&state, addr, &labels, &headers, token, body).await?;
+    let mut resp = if stream {
+        process::chat_completion_stream(reply, include_usage)
+    } else {
+        process::chat_completion(reply.collect().await?)
+    };
+    resp.headers_mut().extend(rate_limit);
+    Ok(resp)
+}
+
+pub async fn completions(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    WithRejection(Json(body), _): WithRejection<Json<CompletionRequest>, Error>,
+) -> crate::Result<Response> {
+    let stream = body.stream();
+    let include_usage = body.include_usage();
+    let echo = body.echo();
+    let body = body.into_chat_request()?;
+    validate_stop(&body)?;
+
+    let token = bearer.as_deref().map(|b| b.token());
+    let (reply, rate_limit) = chat(&state, addr, &labels, &headers, token, body).await?;
+    let mut resp = if stream {
+        process::text_completion_stream(reply, include_usage, echo)
+    } else {
+        process::text_completion(reply.collect().await?, echo)
+    };
+    resp.headers_mut().extend(rate_limit);
+    Ok(resp)
+}
+
+pub async fn responses(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    WithRejection(Json(body), _): WithRejection<Json<ResponsesRequest>, Error>,
+) -> crate::Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let stream = body.stream();
+    let (turn, body) = body.into_turn(&state.responses(), owner).await?;
+
+    let token = bearer.as_deref().map(|b| b.token());
+    let (reply, rate_limit) = chat(&state, addr, &labels, &headers, token, body).await?;
+    let mut resp = if stream {
+        turn.stream(reply)
+    } else {
+        turn.respond(reply.collect().await?).await
+    };
+    resp.headers_mut().extend(rate_limit);
+    Ok(resp)
+}
+
+pub async fn upload_file(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    multipart: Multipart,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let file = state.batches().upload(owner, multipart).await?;
+    Ok(Json(file).into_response())
+}
+
+pub async fn list_files(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<ListFiles>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let files = state.batches().files(owner.as_deref(), &query);
+    Ok(Json(files).into_response())
+}
+
+pub async fn retrieve_file(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(file_id): Path<String>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let file = state.batches().file(&file_id, owner.as_deref())?;
+    Ok(Json(file).into_response())
+}
+
+pub async fn delete_file(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(file_id): Path<String>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let deleted = state
+        .batches()
+        .delete_file(&file_id, owner.as_deref())
+        .await?;
+    Ok(Json(deleted).into_response())
+}
+
+pub async fn file_content(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(file_id): Path<String>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let content = state
+        .batches()
+        .file_content(&file_id, owner.as_deref())
+        .await?;
+    let content_type = [(axum::http::header::CONTENT_TYPE, "application/octet-stream")];
+    Ok((content_type, content).into_response())
+}
+
+pub async fn create_batch(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    WithRejection(Json(body), _): WithRejection<Json<CreateBatch>, Error>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let batch = batch::create(&state, owner, body).await?;
+    Ok(Json(batch).into_response())
+}
+
+pub async fn list_batches(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<ListBatches>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let batches = state.batches().batches(owner.as_deref(), &query).await;
+    Ok(Json(batches).into_response())
+}
+
+pub async fn retrieve_batch(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(batch_id): Path<String>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let batch = state.batches().batch(&batch_id, owner.as_deref()).await?;
+    Ok(Json(batch).into_response())
+}
+
+pub async fn cancel_batch(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Path(batch_id): Path<String>,
+) -> Result<Response> {
+    let owner = owner(&state, bearer.as_ref())?;
+    let batch = state.batches().cancel(&batch_id, owner.as_deref()).await?;
+    Ok(Json(batch).into_response())
+}
+
+/// Name of the authenticated API key, stored responses, files and batches
+/// belong to the key that created them
+fn owner(
+    state: &AppState,
+    bearer: Option<&TypedHeader<Authorization<Bearer>>>,
+) -> Result<Option<String>> {
+    let key = state
+        .load()
+        .authenticate(bearer)?
+        .map(|key| key.name().to_owned());
+    Ok(key)
+}
+
+pub async fn messages(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    body: std::result::Result<Json<MessagesRequest>, JsonRejection>,
+) -> Response {
+    let resp = async {
+        let Json(body) = body?;
+        let stream = body.stream();
+        let body = body.into_chat_request()?;
+
+        // Anthropic clients send their key in `x-api-key`
+        let token = bearer.as_deref().map(|b| b.token()).or_else(|| {
+            headers
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+        });
+        let (reply, rate_limit) = chat(&state, addr, &labels, &headers, token, body).await?;
+        let mut resp = if stream {
+            anthropic::message_stream(reply)
+        } else {
+            anthropic::message(reply.collect().await?)
+        };
+        resp.headers_mut().extend(rate_limit);
+        crate::Result::Ok(resp)
+    };
+
+    resp.await.unwrap_or_else(anthropic::error_response)
+}
+
+pub async fn ollama_chat(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    body: std::result::Result<Json<ollama::ChatRequest>, JsonRejection>,
+) -> Response {
+    let request = body
+        .map_err(Error::from)
+        .and_then(|Json(body)| body.into_request());
+    ollama_reply(&state, addr, &labels, &headers, bearer, request)
+        .await
+        .unwrap_or_else(ollama::error_response)
+}
+
+pub async fn ollama_generate(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(labels): Extension<RequestLabels>,
+    headers: HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    body: std::result::Result<Json<ollama::GenerateRequest>, JsonRejection>,
+) -> Response {
+    let request = body
+        .map_err(Error::from)
+        .and_then(|Json(body)| body.into_request());
+    ollama_reply(&state, addr, &labels, &headers, bearer, request)
+        .await
+        .unwrap_or_else(ollama::error_response)
+}
+
+async fn ollama_reply(
+    state: &AppState,
+    addr: SocketAddr,
+    labels: &RequestLabels,
+    headers: &HeaderMap,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    request: Result<ollama::Request>,
+) -> Result<Response> {
+    let started = Instant::now();
+    let ollama::Request {
+        api,
+        model,
+        stream,
+        body,
+    } = request?;
+
+    let token = bearer.as_deref().map(|b| b.token());
+    let (reply, rate_limit) = chat(state, addr, labels, headers, token, body).await?;
+    let mut resp = if stream {
+        ollama::stream(api, model, started, reply)
+    } else {
+        ollama::response(api, &model, started, reply.collect().await?)
+    };
+    resp.headers_mut().extend(rate_limit);
+    Ok(resp)
+}
+
+pub async fn ollama_tags(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Response {
+    let state = state.load();
+    match state.authenticate(bearer.as_ref()) {
+        Ok(key) => Json(ollama::tags(
+            state
+                .models()
+                .iter()
+                .filter(|model| key.map_or(true, |key| key.allows(model))),
+        ))
+        .into_response(),
+        Err(err) => ollama::error_response(err),
+    }
+}
+
+pub async fn ollama_show(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    body: std::result::Result<Json<ollama::ShowRequest>, JsonRejection>,
+) -> Response {
+    let state = state.load();
+    let show = state.authenticate(bearer.as_ref()).and_then(|key| {
+        let Json(body) = body?;
+        ollama::show(state.models(), key, &body).map(Json)
+    });
+    match show {
+        Ok(show) => show.into_response(),
+        Err(err) => ollama::error_response(err),
+    }
+}
+
+/// Authenticate, rate limit and send a chat request, shared by the API
+/// front-ends once their request is translated, returns the reply along with
+/// the rate limit headers
+async fn chat(
+    state: &AppState,
+    addr: SocketAddr,
+    labels: &RequestLabels,
+    headers: &HeaderMap,
+    token: Option<&str>,
+    mut body: ChatRequest,
+) -> Result<(Reply, HeaderMap)> {
+    let started = Instant::now();
+    let limiter = state.limiter();
+    let state = state.load();
+    let affinity = state.affinity_key(headers, token, body.user());
+    let key = state.authenticate_token(token)?;
+    body.validate()?;
+    // Requests the key may not send are rejected before they count against
+    // its limits
+    let model = resolve_model(&state, key, &mut body)?;
+    labels.set_model(&model.id);
+    let permit = limiter
+        .acquire(state.rate_limit(), key, state.client_ip(headers, addr))
+        .await?;
+    let rate_limit = permit.headers();
+    let reply = chat_reply(
+        &state,
+        model,
+        affinity.as_deref(),
+        Some(permit),
+        started,
+        body,
+    )
+    .await?;
+    Ok((reply, rate_limit))
+}
+
+/// Run a batch request on behalf of the key that created the batch, within the
+/// limits held by `permit`, the reply is collected into a chat completion response
+pub async fn batch_chat(
+    state: &Runtime,
+    owner: Option<&str>,
+    permit: Option<Permit>,
+    mut body: ChatRequest,
+) -> Response {
+    let resp = async move {
+        let key = owner.map(|name| state.api_key(name)).transpose()?;
+        validate_stop(&body)?;
+        body.validate()?;
+        let model = resolve_model(state, key, &mut body)?;
+        let reply = chat_reply(state, model, None, permit, Instant::now(), body).await?;
+        crate::Result::Ok(process::chat_completion(reply.collect().await?))
+    };
+
+    resp.await.unwrap_or_else(IntoResponse::into_response)
+}
+
+/// OpenAI limits `stop` to 4 sequences, the other APIs take any number
+fn validate_stop(body: &ChatRequest) -> Result<()> {
+    if body.stop().len() > 4 {
+        return Err(Error::InvalidParameter(
+            "stop",
+            "'stop' must contain at most 4 sequences".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve the model of the request, rejecting models the key may not use
+fn resolve_model<'a>(
+    state: &'a Runtime,
+    key: Option<&ApiKey>,
+    body: &mut ChatRequest,
+) -> Result<&'a ModelConfig> {
+    let model = body.resolve_model(state.models())?;
+    if let Some(key) = key {
+        key.allow_model(model)?;
+    }
+    Ok(model)
+}
+
+/// Send a validated chat request for the resolved model, asking the upstream
+/// again while the reply does not match its `response_format`
+async fn chat_reply(
+    state: &Runtime,
+    model: &ModelConfig,
+    affinity: Option<&str>,
+    permit: Option<Permit>,
+    started: Instant,
+    mut body: ChatRequest,
+) -> Result<Reply> {
+    let tools = body.apply_tools();
+    let format = body.apply_response_format()?;
+    // Usage counts the tool and format prompts sent upstream, on the turns of
+    // the caller before they are reshaped for the upstream
+    let tokenizer = Tokenizer::for_model(model);
+    let mut prompt_tokens = tokenizer.count_messages(body.messages()).await;
+    // Re-asks are added to the turns of the caller, which are reshaped again
+    // before each attempt
+    let mut turns = match format {
+        Some(_) => body.messages().to_vec(),
+        None => Vec::new(),
+    };
+    body.apply_message_mode(state.message_mode());
+
+    let (raw_model, stop, max_tokens) = (body.model(), body.stop(), body.max_tokens());
+    let process = |resp: rquest::Response,
+                   in_flight: InFlight,
+                   permit: Option<Permit>,
+                   prompt_tokens: usize| {
+        ChatProcess::builder()
+            .resp(resp)
+            .in_flight(in_flight)
+            .permit(permit)
+            .model(raw_model.clone())
+            .tokenizer(tokenizer)
+            .prompt_tokens(prompt_tokens)
+            .output(OutputLimit::new(stop.clone(), max_tokens, tokenizer))
+            .tools(tools.clone())
+            .started(started)
+            .build()
+    };
+
+    let Some(format) = format else {
+        let (resp, in_flight) = send_with_retry(state, &body, affinity).await?;
+        return Ok(Reply::Upstream(process(
+            resp,
+            in_flight,
+            permit,
+            prompt_tokens,
+        )));
+    };
+
+    // The whole reply is validated before anything is sent, streamed replies
+    // are sent at once
+    let mut reasks = 0;
+    loop {
+        let (resp, in_flight) = send_with_retry(state, &body, affinity).await?;
+        let mut completion = process(resp, in_flight, None, prompt_tokens)
+            .collect()
+            .await?;
+        if completion.has_tool_calls() {
+            return Ok(Reply::Complete(completion));
+        }
+
+        match format.extract(completion.text()) {
+            Ok(json) => {
+                completion.set_text(json);
+                return Ok(Reply::Complete(completion));
+            }
+            Err(reason) if reasks < state.response_format().max_reasks => {
+                tracing::warn!("reply does not match response_format, asking again: {reason}");
+                reasks += 1;
+                push_reask(&mut turns, completion.into_text(), format.reask(&reason));
+                // Every attempt sends the whole conversation again
+                prompt_tokens += tokenizer.count_messages(&turns).await;
+                body.set_messages(turns.clone());
+                body.apply_message_mode(state.message_mode());
+            }
+            Err(reason) => return Err(Error::InvalidResponseFormat(reason)),
+        }
+    }
+}
+
+/// Send the request, retrying retryable failures on other clients, returns
+/// the response along with its in flight guard
+async fn send_with_retry(
+    state: &Runtime,
+    body: &ChatRequest,
+    affinity: Option<&str>,
+) -> Result<(rquest::Response, InFlight)> {
+    let retry = state.retry();
+    let mut attempt = 1;
+    loop {
+        // Retries rotate to another client instead of the pinned one
+        let client = if attempt == 1 {
+            state.load_client_with(affinity).await
+        } else {
+            state.load_client().await
+        };
+        tracing::Span::current().record("client", client.name());
+        let in_flight = client.begin_request();
+        match try_request(&client, body, attempt).await {
+            Ok(resp) => {
+                client.record_success();
+                return Ok((resp, in_flight));
+            }
+            Err(err) => {
+                record_upstream_error(&err);
+                if !is_retryable(retry, &err) {
+                    return Err(err);
+                }
+
+                client.record_failure();
+                if attempt >= retry.max_attempts {
+                    return Err(err);
+                }
+
+                let backoff = retry.backoff(attempt);
+                tracing::warn!(
+                    "{}: attempt {attempt} failed, retrying in {backoff:?}: {err}",
+                    client.name()
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+pub async fn token_stats(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Response> {
+    let state = state.load();
+    state.authenticate(bearer.as_ref())?;
+    Ok(Json(state.stats()).into_response())
+}
+
+pub async fn metrics(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<String> {
+    let runtime = state.load();
+    runtime.authenticate(bearer.as_ref())?;
+    Ok(state.metrics().render(runtime.stats()))
+}
+
+/// Count a failed attempt by upstream status or failure kind
+fn record_upstream_error(err: &Error) {
+    let status = match err {
+        Error::UpstreamStatus(status, _) => status.as_str().to_owned(),
+        Error::RequestError(err) => match err.status() {
+            Some(status) => status.as_str().to_owned(),
+            None if err.is_timeout() => "timeout".to_owned(),
+            None if err.is_connect() => "connect".to_owned(),
+            None => "request".to_owned(),
+        },
+        Error::MissingHeader => "missing_vqd".to_owned(),
+        _ => return,
+    };
+    metrics::upstream_error(&status);
+}
+
+/// Whether a failed attempt may be retried on another client
+fn is_retryable(retry: &RetryConfig, err: &Error) -> bool {
+    match err {
+        Error::UpstreamStatus(status, _) => retry.is_retryable_status(status.as_u16()),
+        Error::RequestError(err) => match err.status() {
+            Some(status) => retry.is_retryable_status(status.as_u16()),
+            None => err.is_connect() || err.is_timeout() || err.is_request(),
+        },
+        Error::MissingHeader => true,
+        _ => false,
+    }
+}
+
+async fn try_request(
+    client: &Arc<PoolClient>,
+    body: &ChatRequest,
+    attempt: usize,
+) -> Result<rquest::Response> {
+    // Retries bypass the token pool, the stored tokens may have been rejected
+    let token = if attempt > 1 {
+        client.fetch_token().await?
+    } else {
+        client.acquire_token().await?
+    };
     let span = tracing::info_span!("x-vqd-4", token);
     send_request(client, token, body).instrument(span).await
 }
 
-async fn send_request(client: Client, token: String, body: ChatRequest) -> Result<Response> {
+async fn send_request(
+    client: &PoolClient,
+    token: String,
+    body: &ChatRequest,
+) -> Result<rquest::Response> {
     let resp = client
+        .client()
         .post("https://duckduckgo.com/duckchat/v1/chat")
         .header(header::ACCEPT, "text/event-stream")
         .header(header::ORIGIN, ORIGIN_API)
         .header(header::REFERER, ORIGIN_API)
         .header("x-vqd-4", token)
-        .json(&body)
+        .json(body)
         .send()
         .await?;
 
-    ChatProcess::builder()
-        .resp(resp)
-        .stream(body.stream())
-        .model(body.model())
-        .build()
-        .into_response()
-        .await
-}
-
-async fn load_token(client: &Client) -> Result<String> {
-    let resp = client
-        .get("https://duckduckgo.com/duckchat/v1/status")
-        .header(header::REFERER, ORIGIN_API)
-        .header("x-vqd-accept", "1")
-        .send()
-        .await?
-        .error_for_status()?;
-
-    let token = resp
-        .headers()
-        .get("x-vqd-4")
-        .and_then(|header| header.to_str().ok())
-        .ok_or_else(|| crate::Error::MissingHeader)?;
-
-    Ok(token.to_string())
+    // Keep the rotated token for the next request, or drop the stored tokens
+    // when the upstream rejects the one we sent
+    match resp.status() {
+        status if status.is_success() => {
+            if let Some(token) = resp
+                .headers()
+                .get("x-vqd-4")
+                .and_then(|header| header.to_str().ok())
+            {
+                client.release_token(token.to_owned());
+            }
+            Ok(resp)
+        }
+        status => {
+            if matches!(status, StatusCode::BAD_REQUEST | StatusCode::IM_A_TEAPOT) {
+                tracing::warn!(
+                    "{}: upstream rejected x-vqd-4 token ({status})",
+                    client.name()
+                );
+                client.invalidate_tokens();
+            }
+            let bad_data = resp.text().await?;
+            Err(Error::UpstreamStatus(status, bad_data))
+        }
+    }
 }
 
-mod process {
+pub mod process {
 
-    use crate::serve::model::{
-        ChatCompletion, Choice, Content, DuckChatCompletion, Message, Role, Usage,
+    use crate::config::Tokenizer;
+    use crate::serve::{
+        client::InFlight,
+        limit::Permit,
+        metrics::StreamTimer,
+        model::{
+            default_id, ChatCompletion, Choice, Content, DuckChatCompletion, Message, Role,
+            TextChoice, TextCompletion, ToolCall, Usage,
+        },
+        output::OutputLimit,
+        tools::{ToolParser, ToolReply},
+        ErrorBody,
     };
     use axum::{
         response::{sse::Event, IntoResponse, Response, Sse},
         Error, Json,
     };
     use eventsource_stream::Eventsource;
-    use futures_util::{Stream, StreamExt};
+    use futures_util::{stream::BoxStream, Stream, StreamExt};
+    use rquest::StatusCode;
+    use std::time::Instant;
 
     type EventResult = Result<Event, axum::Error>;
 
+    /// Reply event, independent of the API the request came from
+    pub enum Delta {
+        /// Always the first event
+        Start {
+            id: String,
+            created: u64,
+        },
+        Text(String),
+        ToolCalls(Vec<ToolCall>),
+        /// Upstream failure, the reply is cut short
+        Error(crate::Error),
+        /// Always the last event
+        Finish {
+            finish_reason: &'static str,
+            stop_sequence: Option<String>,
+            usage: Usage,
+        },
+    }
+
+    /// Reply of a chat request, either still streamed from the upstream or
+    /// already read and checked by the proxy
+    pub enum Reply {
+        Upstream(ChatProcess),
+        Complete(Completion),
+    }
+
+    impl Reply {
+        /// Model the reply is reported with
+        pub fn model(&self) -> &str {
+            match self {
+                Reply::Upstream(process) => &process.model,
+                Reply::Complete(completion) => &completion.model,
+            }
+        }
+
+        pub fn prompt_tokens(&self) -> usize {
+            match self {
+                Reply::Upstream(process) => process.prompt_tokens,
+                Reply::Complete(completion) => completion.prompt_tokens,
+            }
+        }
+
+        pub fn deltas(self) -> BoxStream<'static, Delta> {
+            match self {
+                Reply::Upstream(process) => process.deltas().boxed(),
+                Reply::Complete(completion) => completion.deltas().boxed(),
+            }
+        }
+
+        pub async fn collect(self) -> crate::Result<Completion> {
+            match self {
+                Reply::Upstream(process) => process.collect().await,
+                Reply::Complete(completion) => Ok(completion),
+            }
+        }
+    }
+
     #[derive(typed_builder::TypedBuilder)]
     pub struct ChatProcess {
-        stream: Option<bool>,
         model: String,
+        tokenizer: Tokenizer,
+        prompt_tokens: usize,
+        output: OutputLimit,
+        tools: Option<ToolParser>,
         resp: rquest::Response,
+        in_flight: InFlight,
+        permit: Option<Permit>,
+        started: Instant,
     }
 
     impl ChatProcess {
-        pub async fn into_response(self) -> crate::Result<Response> {
-            if self.resp.error_for_status_ref().err().is_some() {
-                let bad_data = self.resp.text().await?;
-                return Err(crate::Error::BadRequest(bad_data));
-            }
-
+        /// Stream the reply as it is generated
+        pub fn deltas(self) -> impl Stream<Item = Delta> {
             let raw_model = self.model;
+            let mut output = self.output;
+            let mut timer = StreamTimer::new(raw_model.clone(), self.started);
+            let chunks = upstream_chunks(self.resp);
 
-            if self.stream.unwrap_or_default() {
-                let mut first_message = true;
-                let sse_stream = process_stream_with_chunk(
-                    self.resp,
-                    move |body| {
-                        if let Some(content) = body.message {
-                            let role = if first_message {
-                                first_message = false;
-                                Some(Role::Assistant)
-                            } else {
-                                None
-                            };
-
-                            let chat_completion = ChatCompletion::builder()
-                                .id(body.id)
-                                .model(&raw_model)
-                                .object("chat.completion.chunk")
-                                .created(body.created)
-                                .choices(vec![Choice::builder()
-                                    .index(0)
-                                    .delta(
-                                        Message::builder()
-                                            .role(role)
-                                            .content(Content::Text(content))
-                                            .build(),
-                                    )
-                                    .logprobs(None)
-                                    .finish_reason(None)
-                                    .build()])
-                                .build();
+            // Keep the request in flight and its rate limit slots until
+            // the stream is dropped
+            let guards = (self.in_flight, self.permit);
+            let (prompt_tokens, tokenizer) = (self.prompt_tokens, self.tokenizer);
+            let tools = self.tools;
+            async_stream::stream! {
+                let _guards = guards;
+                let mut tool_stream = tools.as_ref().map(ToolParser::stream);
+                let mut started = false;
+                let mut failure = None;
 
-                            return Event::default()
-                                .json_data(chat_completion)
-                                .map_err(Error::new);
+                // Dropping the upstream chunks once a limit is reached
+                // cancels the upstream response
+                futures_util::pin_mut!(chunks);
+                while let Some(chunk) = chunks.next().await {
+                    let body = match chunk {
+                        Ok(body) => body,
+                        Err(err) => {
+                            tracing::warn!("upstream stream failed: {err}");
+                            failure = Some(err);
+                            break;
                         }
+                    };
 
-                        let chat_completion = ChatCompletion::builder()
-                            .id(body.id)
-                            .model(&raw_model)
-                            .object("chat.completion.chunk")
-                            .created(body.created)
-                            .choices(vec![Choice::builder()
-                                .index(0)
-                                .delta(Message::default())
-                                .logprobs(None)
-                                .finish_reason("stop")
-                                .build()])
-                            .build();
+                    if !started {
+                        started = true;
+                        yield Delta::Start { id: body.id, created: body.created };
+                    }
 
+                    let Some(content) = body.message else {
                         if let Some(ref model) = body.model {
                             tracing::info!("model mapper: {} -> {}", raw_model, model);
                         }
+                        continue;
+                    };
 
-                        Event::default()
-                            .json_data(chat_completion)
-                            .map_err(Error::new)
-                    },
-                    |event| Ok(Event::default().data(event.data)),
-                );
-                return Ok(Sse::new(sse_stream).into_response());
+                    timer.first_token();
+                    let mut content = output.push(&content);
+                    if let Some(ref mut tool_stream) = tool_stream {
+                        content = tool_stream.push(&content);
+                    }
+                    if !content.is_empty() {
+                        yield Delta::Text(content);
+                    }
+
+                    if output.is_finished() {
+                        break;
+                    }
+                }
+
+                if !started {
+                    yield Delta::Start { id: default_id(), created: 0 };
+                }
+
+                // Text held back for a possible stop sequence or tool call
+                // is still part of the output
+                let mut content = output.finish();
+                let mut tool_calls = None;
+                if let Some(ref mut tool_stream) = tool_stream {
+                    content = tool_stream.push(&content);
+                    match tool_stream.finish() {
+                        ToolReply::Text(text) => content.push_str(&text),
+                        ToolReply::Calls(calls) => tool_calls = Some(calls),
+                    }
+                }
+
+                if !content.is_empty() {
+                    yield Delta::Text(content);
+                }
+
+                let has_tool_calls = tool_calls.is_some();
+                if let Some(calls) = tool_calls {
+                    yield Delta::ToolCalls(calls);
+                }
+
+                let finish_reason = match failure {
+                    Some(err) => {
+                        let finish_reason = failure_finish_reason(&err);
+                        yield Delta::Error(err);
+                        finish_reason
+                    }
+                    None if has_tool_calls => "tool_calls",
+                    None => output.finish_reason().unwrap_or("stop"),
+                };
+                yield Delta::Finish {
+                    finish_reason,
+                    stop_sequence: output.stop_sequence().map(ToOwned::to_owned),
+                    usage: Usage::new(prompt_tokens, tokenizer.count(output.text())),
+                };
             }
+        }
+
+        /// Read the whole reply, the request is released once it is read
+        pub async fn collect(self) -> crate::Result<Completion> {
+            let raw_model = self.model;
+            let mut output = self.output;
 
             let mut id = None;
             let mut created = None;
             let mut model = None;
-            let mut content = String::new();
-            process_stream(self.resp, |body| {
+
+            let chunks = upstream_chunks(self.resp);
+            futures_util::pin_mut!(chunks);
+            while let Some(body) = chunks.next().await {
+                let body = body?;
+
                 // Update id
                 if id.is_none() {
                     id = Some(body.id);
@@ -223,101 +907,453 @@ mod process {
                     model = Some(body.model);
                 }
 
-                // Append chat message
+                // Append chat message, stop reading once a limit is reached
                 if let Some(message) = body.message {
-                    content.push_str(&message);
+                    output.push(&message);
+                    if output.is_finished() {
+                        break;
+                    }
                 }
-            })
-            .await;
+            }
+            output.finish();
 
             if let Some(Some(model)) = model {
                 tracing::info!("model mapper: {} -> {}", raw_model, model);
             }
 
-            let chat_completion = ChatCompletion::builder()
-                .id(id)
-                .model(&raw_model)
-                .object("chat.completion")
-                .created(created)
-                .choices(vec![Choice::builder()
-                    .index(0)
-                    .message(
-                        Message::builder()
-                            .role(Role::Assistant)
-                            .content(Content::Text(content))
-                            .build(),
-                    )
-                    .logprobs(None)
-                    .finish_reason("stop")
-                    .build()])
-                .usage(
-                    Usage::builder()
-                        .completion_tokens(0)
-                        .prompt_tokens(0)
-                        .total_tokens(0)
-                        .build(),
-                )
-                .build();
+            let tool_calls = self
+                .tools
+                .as_ref()
+                .and_then(|tools| tools.parse(output.text()));
 
-            Ok(Json(chat_completion).into_response())
+            Ok(Completion {
+                model: raw_model,
+                tokenizer: self.tokenizer,
+                prompt_tokens: self.prompt_tokens,
+                id: id.unwrap_or_else(default_id),
+                created: created.unwrap_or_default(),
+                text: output.text().to_owned(),
+                finish_reason: output.finish_reason().unwrap_or("stop"),
+                stop_sequence: output.stop_sequence().map(ToOwned::to_owned),
+                tool_calls,
+            })
         }
     }
 
-    async fn process_stream<H>(resp: rquest::Response, mut handler: H)
-    where
-        H: FnMut(DuckChatCompletion),
-    {
-        let mut event_source = resp.bytes_stream().eventsource();
-        while let Some(event_result) = event_source.next().await {
-            match event_result {
-                Ok(event) => {
-                    if event.data.eq("[DONE]") {
-                        break;
+    /// Whole reply of the upstream
+    pub struct Completion {
+        model: String,
+        tokenizer: Tokenizer,
+        prompt_tokens: usize,
+        id: String,
+        created: u64,
+        text: String,
+        finish_reason: &'static str,
+        stop_sequence: Option<String>,
+        tool_calls: Option<Vec<ToolCall>>,
+    }
+
+    impl Completion {
+        #[inline]
+        pub fn model(&self) -> &str {
+            &self.model
+        }
+
+        #[inline]
+        pub fn text(&self) -> &str {
+            &self.text
+        }
+
+        #[inline]
+        pub fn into_text(self) -> String {
+            self.text
+        }
+
+        /// Replace the reply text, the usage is counted on the new text
+        #[inline]
+        pub fn set_text(&mut self, text: String) {
+            self.text = text;
+        }
+
+        #[inline]
+        pub fn tool_calls(&self) -> Option<&[ToolCall]> {
+            self.tool_calls.as_deref()
+        }
+
+        #[inline]
+        pub fn has_tool_calls(&self) -> bool {
+            self.tool_calls.is_some()
+        }
+
+        /// `tool_calls`, `stop` or `length`
+        pub fn finish_reason(&self) -> &'static str {
+            match self.tool_calls {
+                Some(_) => "tool_calls",
+                None => self.finish_reason,
+            }
+        }
+
+        #[inline]
+        pub fn stop_sequence(&self) -> Option<&str> {
+            self.stop_sequence.as_deref()
+        }
+
+        pub fn usage(&self) -> Usage {
+            Usage::new(self.prompt_tokens, self.tokenizer.count(&self.text))
+        }
+
+        /// Replay the reply as stream events
+        pub fn deltas(self) -> impl Stream<Item = Delta> {
+            let finish = Delta::Finish {
+                finish_reason: self.finish_reason(),
+                stop_sequence: self.stop_sequence.clone(),
+                usage: self.usage(),
+            };
+            let mut deltas = vec![Delta::Start {
+                id: self.id,
+                created: self.created,
+            }];
+            match self.tool_calls {
+                Some(calls) => deltas.push(Delta::ToolCalls(calls)),
+                None if !self.text.is_empty() => deltas.push(Delta::Text(self.text)),
+                None => {}
+            }
+            deltas.push(finish);
+            futures_util::stream::iter(deltas)
+        }
+    }
+
+    /// OpenAI chat completion chunks of a reply
+    pub fn chat_completion_stream(reply: Reply, include_usage: bool) -> Response {
+        let raw_model = reply.model().to_owned();
+        let deltas = reply.deltas();
+        let sse_stream = async_stream::stream! {
+            let mut first_message = true;
+            let mut id = default_id();
+            let mut created = 0;
+
+            futures_util::pin_mut!(deltas);
+            while let Some(delta) = deltas.next().await {
+                let delta = match delta {
+                    Delta::Start { id: start_id, created: start_created } => {
+                        id = start_id;
+                        created = start_created;
+                        continue;
                     }
-                    match serde_json::from_str::<DuckChatCompletion>(&event.data) {
-                        Ok(body) => handler(body),
-                        Err(err) => {
-                            tracing::warn!("failed to parse upstream body: {err}");
+                    Delta::Text(content) => Message::builder()
+                        .role(first_message.then_some(Role::Assistant))
+                        .content(Content::Text(content))
+                        .build(),
+                    Delta::ToolCalls(calls) => {
+                        for (index, mut call) in calls.into_iter().enumerate() {
+                            call.index = Some(index);
+                            yield chunk_event(
+                                &raw_model,
+                                id.clone(),
+                                created,
+                                Message::builder()
+                                    .role(first_message.then_some(Role::Assistant))
+                                    .tool_calls(vec![call])
+                                    .build(),
+                                None,
+                            );
+                            first_message = false;
+                        }
+                        continue;
+                    }
+                    Delta::Error(err) => {
+                        yield error_event(&err);
+                        continue;
+                    }
+                    Delta::Finish { finish_reason, usage, .. } => {
+                        yield chunk_event(
+                            &raw_model,
+                            id.clone(),
+                            created,
+                            Message::default(),
+                            Some(finish_reason),
+                        );
+                        if include_usage {
+                            yield usage_event(&raw_model, id.clone(), created, usage);
+                        }
+                        continue;
+                    }
+                };
+
+                first_message = false;
+                yield chunk_event(&raw_model, id.clone(), created, delta, None);
+            }
+
+            yield Ok(Event::default().data("[DONE]"));
+        };
+
+        Sse::new(sse_stream).into_response()
+    }
+
+    /// OpenAI chat completion of a whole reply
+    pub fn chat_completion(completion: Completion) -> Response {
+        let usage = completion.usage();
+        let finish_reason = completion.finish_reason();
+        let message = match completion.tool_calls {
+            Some(calls) => Message::builder()
+                .role(Role::Assistant)
+                .tool_calls(calls)
+                .build(),
+            None => Message::builder()
+                .role(Role::Assistant)
+                .content(Content::Text(completion.text))
+                .build(),
+        };
+
+        let chat_completion = ChatCompletion::builder()
+            .id(completion.id)
+            .model(&completion.model)
+            .object("chat.completion")
+            .created(completion.created)
+            .choices(vec![Choice::builder()
+                .index(0)
+                .message(message)
+                .logprobs(None)
+                .finish_reason(finish_reason)
+                .build()])
+            .usage(usage)
+            .build();
+
+        Json(chat_completion).into_response()
+    }
+
+    /// Legacy text completion chunks of a reply
+    pub fn text_completion_stream(
+        reply: Reply,
+        include_usage: bool,
+        echo: Option<String>,
+    ) -> Response {
+        let raw_model = reply.model().to_owned();
+        let deltas = reply.deltas();
+        let sse_stream = async_stream::stream! {
+            let mut id = default_id();
+            let mut created = 0;
+
+            futures_util::pin_mut!(deltas);
+            while let Some(delta) = deltas.next().await {
+                match delta {
+                    Delta::Start { id: start_id, created: start_created } => {
+                        id = start_id;
+                        created = start_created;
+                        if let Some(ref echo) = echo {
+                            yield text_event(&raw_model, &id, created, echo.clone(), None);
+                        }
+                    }
+                    Delta::Text(text) => {
+                        yield text_event(&raw_model, &id, created, text, None);
+                    }
+                    // Tools are not part of the legacy API
+                    Delta::ToolCalls(_) => {}
+                    Delta::Error(err) => yield error_event(&err),
+                    Delta::Finish { finish_reason, usage, .. } => {
+                        yield text_event(
+                            &raw_model,
+                            &id,
+                            created,
+                            String::new(),
+                            Some(finish_reason),
+                        );
+                        if include_usage {
+                            let completion = TextCompletion::builder()
+                                .id(id.as_str())
+                                .created(created)
+                                .model(&raw_model)
+                                .choices(Vec::new())
+                                .usage(usage)
+                                .build();
+                            yield Event::default().json_data(completion).map_err(Error::new);
                         }
                     }
                 }
-                Err(err) => {
-                    tracing::warn!("failed read upstream bytes stream: {err}")
-                }
             }
+
+            yield Ok(Event::default().data("[DONE]"));
+        };
+
+        Sse::new(sse_stream).into_response()
+    }
+
+    /// Legacy text completion of a whole reply
+    pub fn text_completion(completion: Completion, echo: Option<String>) -> Response {
+        let usage = completion.usage();
+        let finish_reason = completion.finish_reason();
+        let mut text = echo.unwrap_or_default();
+        text.push_str(&completion.text);
+
+        let text_completion = TextCompletion::builder()
+            .id(completion.id)
+            .created(completion.created)
+            .model(&completion.model)
+            .choices(vec![TextChoice::builder()
+                .text(text)
+                .finish_reason(finish_reason)
+                .build()])
+            .usage(usage)
+            .build();
+
+        Json(text_completion).into_response()
+    }
+
+    fn text_event(
+        model: &str,
+        id: &str,
+        created: u64,
+        text: String,
+        finish_reason: Option<&'static str>,
+    ) -> EventResult {
+        let text_completion = TextCompletion::builder()
+            .id(id)
+            .created(created)
+            .model(model)
+            .choices(vec![TextChoice::builder()
+                .text(text)
+                .finish_reason(finish_reason)
+                .build()])
+            .build();
+
+        Event::default()
+            .json_data(text_completion)
+            .map_err(Error::new)
+    }
+
+    fn chunk_event(
+        model: &str,
+        id: String,
+        created: u64,
+        delta: Message,
+        finish_reason: Option<&'static str>,
+    ) -> EventResult {
+        let chat_completion = ChatCompletion::builder()
+            .id(id)
+            .model(model)
+            .object("chat.completion.chunk")
+            .created(created)
+            .choices(vec![Choice::builder()
+                .index(0)
+                .delta(delta)
+                .logprobs(None)
+                .finish_reason(finish_reason)
+                .build()])
+            .build();
+
+        Event::default()
+            .json_data(chat_completion)
+            .map_err(Error::new)
+    }
+
+    /// Final chunk of `stream_options.include_usage`, without choices
+    fn usage_event(model: &str, id: String, created: u64, usage: Usage) -> EventResult {
+        let chat_completion = ChatCompletion::builder()
+            .id(id)
+            .model(model)
+            .object("chat.completion.chunk")
+            .created(created)
+            .choices(Vec::new())
+            .usage(usage)
+            .build();
+
+        Event::default()
+            .json_data(chat_completion)
+            .map_err(Error::new)
+    }
+
+    /// OpenAI-style error event, the SDKs raise it as an API error
+    fn error_event(err: &crate::Error) -> EventResult {
+        let (_, error) = err.to_openai();
+        Event::default()
+            .json_data(ErrorBody { error })
+            .map_err(Error::new)
+    }
+
+    /// Finish reason of a choice cut short by an upstream failure, running
+    /// into the conversation limit is reported as `length`
+    fn failure_finish_reason(err: &crate::Error) -> &'static str {
+        match err {
+            crate::Error::UpstreamStatus(_, body) if body.contains("ERR_CONVERSATION_LIMIT") => {
+                "length"
+            }
+            _ => "stop",
         }
     }
 
-    fn process_stream_with_chunk<S, E>(
+    /// Upstream chunks of a chat response, ends after `[DONE]` or after the
+    /// first failure
+    fn upstream_chunks(
         resp: rquest::Response,
-        mut handler: S,
-        end_handler: E,
-    ) -> impl Stream<Item = EventResult>
-    where
-        S: FnMut(DuckChatCompletion) -> EventResult,
-        E: FnOnce(eventsource_stream::Event) -> EventResult,
-    {
+    ) -> impl Stream<Item = crate::Result<DuckChatCompletion>> {
         let mut event_source = resp.bytes_stream().eventsource();
         async_stream::stream! {
-            while let Some(event_result) = event_source.next().await {
-                match event_result {
-                    Ok(event) => {
+            let mut received = false;
+            loop {
+                match event_source.next().await {
+                    Some(Ok(event)) => {
                         if event.data.eq("[DONE]") {
-                            yield end_handler(event);
                             break;
                         }
-                        match serde_json::from_str::<DuckChatCompletion>(&event.data) {
-                            Ok(body) => yield handler(body),
+                        match parse_chunk(&event.data) {
+                            Ok(Some(body)) => {
+                                received = true;
+                                yield Ok(body)
+                            }
+                            Ok(None) => {}
                             Err(err) => {
-                                tracing::warn!("failed to parse upstream body: {err}");
+                                yield Err(err);
+                                break;
                             }
                         }
                     }
-                    Err(err) => {
-                        tracing::warn!("failed read upstream bytes stream: {err}")
+                    Some(Err(err)) => {
+                        yield Err(crate::Error::UpstreamStream(format!(
+                            "failed read upstream bytes stream: {err}"
+                        )));
+                        break;
+                    }
+                    // A stream closed without `[DONE]` after sending a reply
+                    // is kept, only an empty one is a failure
+                    None if received => {
+                        tracing::warn!("upstream closed the stream without [DONE]");
+                        break;
+                    }
+                    None => {
+                        yield Err(crate::Error::UpstreamStream(
+                            "upstream closed the stream before it completed".to_owned(),
+                        ));
+                        break;
                     }
                 }
             }
         }
     }
+
+    /// Parse an upstream chunk, error actions carry their own status and other
+    /// chunks that are not completions are skipped
+    fn parse_chunk(data: &str) -> crate::Result<Option<DuckChatCompletion>> {
+        #[derive(serde::Deserialize)]
+        struct DuckChatError {
+            action: String,
+            status: Option<u16>,
+        }
+
+        match serde_json::from_str::<DuckChatCompletion>(data) {
+            Ok(body) => Ok(Some(body)),
+            Err(err) => match serde_json::from_str::<DuckChatError>(data) {
+                Ok(error) if error.action == "error" => Err(crate::Error::UpstreamStatus(
+                    error
+                        .status
+                        .and_then(|status| StatusCode::from_u16(status).ok())
+                        .unwrap_or(StatusCode::BAD_GATEWAY),
+                    data.to_owned(),
+                )),
+                _ => {
+                    tracing::warn!("skipping unparseable upstream chunk: {err}");
+                    Ok(None)
+                }
+            },
+        }
+    }
 }
@@ -1,12 +1,14 @@
 use std::time::Duration;
 
+use super::Reloader;
 use axum_server::Handle;
 #[cfg(target_family = "unix")]
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::sleep;
 use tracing::info;
 
-pub(super) async fn graceful_shutdown(handle: Handle) {
+#[cfg_attr(target_family = "windows", allow(unused_mut, unused_variables))]
+pub(super) async fn graceful_shutdown(handle: Handle, mut reloader: Reloader) {
     #[cfg(target_family = "windows")]
     {
         tokio::signal::ctrl_c()
@@ -19,25 +21,26 @@ pub(super) async fn graceful_shutdown(handle: Handle) {
     {
         let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM signal hanlde error");
         let mut sigquit = signal(SignalKind::quit()).expect("SIGQUIT signal hanlde error");
-        let mut sigchld = signal(SignalKind::child()).expect("SIGCHLD signal hanlde error");
         let mut sighup = signal(SignalKind::hangup()).expect("SIGHUP signal hanlde error");
-        tokio::select! {
-            _ = sigterm.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGTERM").await;
-            },
-            _ = sigquit.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGQUIT").await;
-            },
-            _ = sigchld.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGCHLD").await;
-            },
-            _ = sighup.recv() => {
-                sending_graceful_shutdown_signal(handle, "SIGHUP").await;
-            },
-            _ = tokio::signal::ctrl_c() => {
-                sending_graceful_shutdown_signal(handle, "SIGINT").await;
-            }
-        };
+        loop {
+            tokio::select! {
+                _ = sigterm.recv() => {
+                    sending_graceful_shutdown_signal(handle.clone(), "SIGTERM").await;
+                },
+                _ = sigquit.recv() => {
+                    sending_graceful_shutdown_signal(handle.clone(), "SIGQUIT").await;
+                },
+                _ = sighup.recv() => {
+                    info!("SIGHUP received: reloading configuration");
+                    reloader.reload().await;
+                    continue;
+                },
+                _ = tokio::signal::ctrl_c() => {
+                    sending_graceful_shutdown_signal(handle.clone(), "SIGINT").await;
+                }
+            };
+            break;
+        }
     }
 }
 
@@ -0,0 +1,121 @@
+use super::model::Message;
+use crate::config::ModelConfig;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+/// Tokens added per message by the chat format, see
+/// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+const TOKENS_PER_MESSAGE: usize = 3;
+
+/// Tokens priming the assistant reply
+const TOKENS_PER_REPLY: usize = 3;
+
+/// Conversations with more text (bytes) are counted on the blocking pool
+const BLOCKING_THRESHOLD: usize = 64 * 1024;
+
+/// Bundled BPE tokenizer
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    Cl100kBase,
+    O200kBase,
+}
+
+impl Tokenizer {
+    /// Tokenizer of a model, `o200k_base` for the GPT-4o and o-series
+    /// families, `cl100k_base` as an approximation for everything else
+    pub fn for_model(model: &ModelConfig) -> Self {
+        model.tokenizer.unwrap_or_else(|| {
+            let upstream = model.upstream.to_ascii_lowercase();
+            if upstream.starts_with("gpt-4o")
+                || upstream.starts_with("o1")
+                || upstream.starts_with("o3")
+                || upstream.starts_with("o4")
+            {
+                Tokenizer::O200kBase
+            } else {
+                Tokenizer::Cl100kBase
+            }
+        })
+    }
+
+    /// Number of tokens of a text
+    pub fn count(self, text: &str) -> usize {
+        self.bpe().encode_with_special_tokens(text).len()
+    }
+
+    /// Text cut to its first `max_tokens` tokens, `None` if the text has
+    /// fewer tokens
+    pub fn truncate(self, text: &str, max_tokens: usize) -> Option<String> {
+        let bpe = self.bpe();
+        let mut tokens = bpe.encode_with_special_tokens(text);
+        if tokens.len() < max_tokens {
+            return None;
+        }
+
+        // A cut inside a multi-byte character does not decode, drop the
+        // partial tokens
+        tokens.truncate(max_tokens);
+        while !tokens.is_empty() {
+            if let Ok(text) = bpe.decode(tokens.clone()) {
+                return Some(text);
+            }
+            tokens.pop();
+        }
+        Some(String::new())
+    }
+
+    /// Number of prompt tokens of a conversation, large conversations are
+    /// counted on the blocking pool so that they do not stall the runtime
+    pub async fn count_messages(self, messages: &[Message]) -> usize {
+        let size = messages
+            .iter()
+            .filter_map(Message::content)
+            .flat_map(|content| content.texts())
+            .map(str::len)
+            .sum::<usize>();
+        if size < BLOCKING_THRESHOLD {
+            return self.count_messages_blocking(messages);
+        }
+
+        let messages = messages.to_vec();
+        tokio::task::spawn_blocking(move || self.count_messages_blocking(&messages))
+            .await
+            .unwrap_or_default()
+    }
+
+    fn count_messages_blocking(self, messages: &[Message]) -> usize {
+        let bpe = self.bpe();
+        let tokens = messages
+            .iter()
+            .map(|message| {
+                let role = message.role().map_or(0, |role| {
+                    bpe.encode_with_special_tokens(role.as_str()).len()
+                });
+                let content = message.content().map_or(0, |content| {
+                    content
+                        .texts()
+                        .map(|text| bpe.encode_with_special_tokens(text).len())
+                        .sum()
+                });
+                TOKENS_PER_MESSAGE + role + content
+            })
+            .sum::<usize>();
+
+        tokens + TOKENS_PER_REPLY
+    }
+
+    fn bpe(self) -> &'static CoreBPE {
+        static CL100K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+        static O200K_BASE: OnceLock<CoreBPE> = OnceLock::new();
+
+        // The BPE ranks are bundled with the binary, loading them cannot fail
+        match self {
+            Tokenizer::Cl100kBase => CL100K_BASE
+                .get_or_init(|| tiktoken_rs::cl100k_base().expect("bundled cl100k_base ranks")),
+            Tokenizer::O200kBase => O200K_BASE
+                .get_or_init(|| tiktoken_rs::o200k_base().expect("bundled o200k_base ranks")),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Tool calling emulated on top of plain chat turns.
+//!
+//! The tools are described in a system prompt asking the model to reply with
+//! a `{"tool_calls": [...]}` JSON object, the reply is then parsed back into
+//! OpenAI `tool_calls`. Previous calls and their results are rendered into
+//! the same shape so that the model sees consistent examples.
+
+use super::model::{random_id, FunctionCall, Tool, ToolCall, ToolChoice};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// System prompt describing the tools and the expected reply
+pub fn prompt(tools: &[Tool], choice: Option<&ToolChoice>) -> String {
+    let definitions = tools
+        .iter()
+        .map(|tool| serde_json::to_string(&tool.function).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut prompt = format!(
+        "You can call the following tools, described as JSON schemas:\n\
+         <tools>\n{definitions}\n</tools>\n\n\
+         To call tools, reply with a single JSON object and nothing else, no \
+         explanation and no code fence:\n\
+         {{\"tool_calls\": [{{\"name\": \"<tool name>\", \"arguments\": {{<arguments matching the tool parameters>}}}}]}}\n\
+         Several tools may be called at once. The results are sent back in the \
+         next user message, use them to answer."
+    );
+
+    match choice {
+        Some(ToolChoice::Mode(mode)) if mode == "required" => {
+            prompt.push_str("\nYou must call at least one tool.");
+        }
+        Some(ToolChoice::Function { function }) => {
+            prompt.push_str(&format!("\nYou must call the `{}` tool.", function.name));
+        }
+        _ => prompt.push_str("\nIf no tool is needed, reply normally."),
+    }
+
+    prompt
+}
+
+/// Tool calls of an assistant turn, in the shape the model is asked to reply
+pub fn render_calls(calls: &[ToolCall]) -> String {
+    let calls = calls
+        .iter()
+        .map(|call| {
+            let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                .unwrap_or_else(|_| Value::String(call.function.arguments.clone()));
+            serde_json::json!({ "name": call.function.name, "arguments": arguments })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({ "tool_calls": calls }).to_string()
+}
+
+/// Result of a tool call sent back by the user
+pub fn render_result(id: Option<&str>, name: Option<&str>, content: &str) -> String {
+    match (name, id) {
+        (Some(name), None) => format!("Result of the `{name}` tool call:\n{content}"),
+        (Some(name), Some(id)) => format!("Result of the `{name}` tool call ({id}):\n{content}"),
+        (None, Some(id)) => format!("Result of the tool call {id}:\n{content}"),
+        (None, None) => format!("Result of the tool call:\n{content}"),
+    }
+}
+
+/// Parse the model reply into tool calls
+#[derive(Clone)]
+pub struct ToolParser {
+    names: Vec<String>,
+}
+
+impl ToolParser {
+    pub fn new(tools: &[Tool]) -> Self {
+        Self {
+            names: tools
+                .iter()
+                .map(|tool| tool.function.name.clone())
+                .collect(),
+        }
+    }
+
+    /// Tool calls of the reply, `None` if the reply is plain text or names an
+    /// unknown tool
+    pub fn parse(&self, text: &str) -> Option<Vec<ToolCall>> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Reply {
+            Calls { tool_calls: Vec<Call> },
+            Call(Call),
+        }
+
+        #[derive(Deserialize)]
+        struct Call {
+            name: String,
+            #[serde(default)]
+            arguments: Value,
+        }
+
+        let calls = match serde_json::from_str::<Reply>(strip_fence(text)).ok()? {
+            Reply::Calls { tool_calls } => tool_calls,
+            Reply::Call(call) => vec![call],
+        };
+
+        if calls.is_empty() || !calls.iter().all(|call| self.names.contains(&call.name)) {
+            return None;
+        }
+
+        Some(
+            calls
+                .into_iter()
+                .map(|call| {
+                    let arguments = match call.arguments {
+                        Value::String(arguments) => arguments,
+                        Value::Null => "{}".to_owned(),
+                        arguments => arguments.to_string(),
+                    };
+                    ToolCall::builder()
+                        .id(random_id("call"))
+                        .function(FunctionCall {
+                            name: call.name,
+                            arguments,
+                        })
+                        .build()
+                })
+                .collect(),
+        )
+    }
+
+    pub fn stream(&self) -> ToolStream<'_> {
+        ToolStream {
+            parser: self,
+            state: State::Pending,
+            buffer: String::new(),
+        }
+    }
+}
+
+/// Streamed reply, text is passed through as soon as it cannot be a tool call
+pub struct ToolStream<'a> {
+    parser: &'a ToolParser,
+    state: State,
+    buffer: String,
+}
+
+enum State {
+    /// Only whitespace so far
+    Pending,
+    /// Looks like JSON, held until the reply ends
+    Buffering,
+    /// Plain text
+    Text,
+}
+
+/// End of a streamed reply
+pub enum ToolReply {
+    Text(String),
+    Calls(Vec<ToolCall>),
+}
+
+impl ToolStream<'_> {
+    /// Feed reply text, returns the text that may be sent
+    pub fn push(&mut self, delta: &str) -> String {
+        match self.state {
+            State::Text => return delta.to_owned(),
+            State::Buffering => {
+                self.buffer.push_str(delta);
+                return String::new();
+            }
+            State::Pending => self.buffer.push_str(delta),
+        }
+
+        let start = self.buffer.trim_start();
+        if start.is_empty() {
+            String::new()
+        } else if start.starts_with('{') || start.starts_with('`') {
+            self.state = State::Buffering;
+            String::new()
+        } else {
+            self.state = State::Text;
+            std::mem::take(&mut self.buffer)
+        }
+    }
+
+    /// Held text or the parsed tool calls once the reply ended
+    pub fn finish(&mut self) -> ToolReply {
+        let buffer = std::mem::take(&mut self.buffer);
+        match self.state {
+            State::Buffering => match self.parser.parse(&buffer) {
+                Some(calls) => ToolReply::Calls(calls),
+                None => ToolReply::Text(buffer),
+            },
+            _ => ToolReply::Text(buffer),
+        }
+    }
+}
+
+/// Strip a markdown code fence around the reply
+pub fn strip_fence(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix("```")
+        .and_then(|text| text.strip_suffix("```"))
+        .map(|text| text.trim_start_matches("json").trim())
+        .unwrap_or(text)
+}